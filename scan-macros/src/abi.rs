@@ -0,0 +1,93 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    parse_quote, Attribute, Data, DeriveInput, Error, Fields, Ident, Result, Type, WherePredicate,
+};
+
+const UNSUPPORTED: &str = "#[derive(AsCraneliftAbi)] needs a `#[repr(transparent)]` struct or enum";
+
+/// The `#[repr(...)]`s of an item.
+fn reprs(attrs: &[Attribute]) -> Result<Vec<Ident>> {
+    let mut reprs = vec![];
+
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if let Some(ident) = meta.path.get_ident() {
+                reprs.push(ident.clone());
+            }
+
+            // Skip over the arguments of `align(N)` and `packed(N)`.
+            if meta.input.peek(syn::token::Paren) {
+                let _content;
+                syn::parenthesized!(_content in meta.input);
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok(reprs)
+}
+
+/// Whether `ty` is a `PhantomData`, which is zero sized and so ignored by `#[repr(transparent)]`.
+fn is_phantom_data(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "PhantomData"),
+        _ => false,
+    }
+}
+
+/// The type of the one non zero sized field of a `#[repr(transparent)]` type.
+fn transparent_field<'a>(fields: &'a Fields, span: &Ident) -> Result<&'a Type> {
+    let mut fields = fields.iter().filter(|field| !is_phantom_data(&field.ty));
+
+    match (fields.next(), fields.next()) {
+        (Some(field), None) => Ok(&field.ty),
+        _ => Err(Error::new_spanned(
+            span,
+            "#[derive(AsCraneliftAbi)] needs exactly one field that isn't `PhantomData`",
+        )),
+    }
+}
+
+pub(crate) fn expand(input: DeriveInput) -> Result<TokenStream> {
+    let reprs = reprs(&input.attrs)?;
+    let transparent = reprs.iter().any(|repr| repr == "transparent");
+
+    let ident = &input.ident;
+
+    let inner: Type = match &input.data {
+        Data::Struct(data) if transparent => transparent_field(&data.fields, ident)?.clone(),
+
+        Data::Enum(data) if transparent => {
+            let [variant] = &data.variants.iter().collect::<Vec<_>>()[..] else {
+                return Err(Error::new_spanned(
+                    ident,
+                    "#[repr(transparent)] enums must have exactly one variant",
+                ));
+            };
+
+            transparent_field(&variant.fields, ident)?.clone()
+        }
+
+        _ => return Err(Error::new_spanned(ident, UNSUPPORTED)),
+    };
+
+    let mut generics = input.generics.clone();
+    let bound: WherePredicate = parse_quote!(#inner: ::scan::AsCraneliftAbi);
+    generics.make_where_clause().predicates.push(bound);
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::scan::AsCraneliftAbi for #ident #ty_generics #where_clause {
+            fn as_cranelift_abi() -> ::scan::__private::AbiParam {
+                <#inner as ::scan::AsCraneliftAbi>::as_cranelift_abi()
+            }
+        }
+    })
+}
@@ -0,0 +1,71 @@
+mod abi;
+mod vtable;
+
+use proc_macro::TokenStream;
+
+/// Declare a C++ interface by its vtable layout.
+///
+/// The trait is replaced by an opaque `#[repr(C)]` struct of the same name, whose only field is the
+/// vtable pointer. Every method gets a vtable index from its position in the trait, starting at 0.
+/// `#[index = N]` overrides the index of a method, and the methods after it continue from `N + 1`.
+///
+/// For each method `foo` the struct gets:
+/// * `FOO_INDEX`, the vtable index of the method.
+/// * `unsafe fn foo(&self, ...)`, which calls through the vtable, taking `&mut self` if the method
+///   does.
+/// * `fn hook_foo(instance, closure)`, which hooks the method on `instance` with a
+///   [`scan::HookFunction`](../scan/struct.HookFunction.html).
+///
+/// # Example
+///
+/// ```rs
+/// #[vtable]
+/// pub trait IClientEntity {
+///     fn get_origin(&self) -> *const Vec3;
+///     #[index = 37]
+///     fn get_model_index(&self) -> i32;
+/// }
+///
+/// let origin = unsafe { (*entity).get_origin() };
+///
+/// let hook = IClientEntity::hook_get_model_index(entity, |ctx, this: &mut IClientEntity| -> i32 {
+///     call_original(ctx, this, ()) + 1
+/// })?;
+/// ```
+///
+#[proc_macro_attribute]
+pub fn vtable(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = syn::parse_macro_input!(item as syn::ItemTrait);
+
+    vtable::expand(attr.into(), item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Implement `AsCraneliftAbi` for a type that is passed the same way as a type that already
+/// implements it.
+///
+/// This works for:
+/// * `#[repr(transparent)]` structs, like handle newtypes.
+/// * `#[repr(transparent)]` enums with a single variant that has a single field.
+///
+/// Enums without fields aren't supported even with an integer repr, as the hooked function could be
+/// given a value that isn't one of the variants, which is undefined behaviour as soon as the hook is
+/// called with it. Take the integer and convert it instead.
+///
+/// # Example
+///
+/// ```rs
+/// #[derive(AsCraneliftAbi)]
+/// #[repr(transparent)]
+/// struct EntityHandle(u32);
+/// ```
+///
+#[proc_macro_derive(AsCraneliftAbi)]
+pub fn derive_as_cranelift_abi(item: TokenStream) -> TokenStream {
+    let item = syn::parse_macro_input!(item as syn::DeriveInput);
+
+    abi::expand(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
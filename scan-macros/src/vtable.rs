@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{
+    spanned::Spanned, Attribute, Error, Expr, ExprLit, FnArg, Ident, ItemTrait, Lit, Meta, Pat,
+    Receiver, Result, ReturnType, TraitItem, TraitItemFn, Type,
+};
+
+struct Method {
+    attrs: Vec<Attribute>,
+    name: Ident,
+    receiver: Receiver,
+    index: usize,
+    arg_names: Vec<Ident>,
+    arg_types: Vec<Type>,
+    ret: Type,
+}
+
+pub(crate) fn expand(attr: TokenStream, item: ItemTrait) -> Result<TokenStream> {
+    if !attr.is_empty() {
+        return Err(Error::new_spanned(
+            attr,
+            "#[vtable] does not take arguments",
+        ));
+    }
+
+    if !item.generics.params.is_empty() || item.generics.where_clause.is_some() {
+        return Err(Error::new_spanned(
+            &item.generics,
+            "#[vtable] interfaces cannot be generic",
+        ));
+    }
+
+    if !item.supertraits.is_empty() {
+        return Err(Error::new_spanned(
+            &item.supertraits,
+            "#[vtable] interfaces cannot have supertraits",
+        ));
+    }
+
+    let mut methods = vec![];
+    let mut indices = HashMap::<usize, Ident>::new();
+    let mut next_index = 0;
+
+    for trait_item in &item.items {
+        let TraitItem::Fn(method) = trait_item else {
+            return Err(Error::new_spanned(
+                trait_item,
+                "#[vtable] interfaces can only contain methods",
+            ));
+        };
+
+        let method = parse_method(method, next_index)?;
+
+        if let Some(other) = indices.insert(method.index, method.name.clone()) {
+            return Err(Error::new_spanned(
+                &method.name,
+                format!("index {} is already used by `{other}`", method.index),
+            ));
+        }
+
+        next_index = method.index + 1;
+        methods.push(method);
+    }
+
+    let ItemTrait {
+        attrs, vis, ident, ..
+    } = &item;
+
+    let methods = methods.iter().map(|method| {
+        let Method {
+            attrs,
+            name,
+            receiver,
+            index,
+            arg_names,
+            arg_types,
+            ret,
+        } = method;
+
+        // Keep the receiver that was written, so that `&mut self` methods need a unique borrow.
+        let this = if receiver.mutability.is_some() {
+            quote!(self as *mut Self)
+        } else {
+            quote!(self as *const Self as *mut Self)
+        };
+
+        let index_name = format_ident!("{}_INDEX", name.to_string().to_uppercase());
+        let hook_name = format_ident!("hook_{}", name);
+
+        let index_doc = format!("Vtable index of [`Self::{name}`].");
+        let hook_doc = format!(
+            "Hook [`Self::{name}`] on `instance`, see [`scan::HookFunction::new`] for details."
+        );
+
+        quote! {
+            #[doc = #index_doc]
+            #vis const #index_name: usize = #index;
+
+            #(#attrs)*
+            ///
+            /// # Safety
+            /// `self` must be a live instance whose vtable matches this declaration.
+            ///
+            #vis unsafe fn #name(#receiver, #(#arg_names: #arg_types),*) -> #ret {
+                ::scan::call_virtual::<#ret, (#(#arg_types,)*)>(
+                    #this,
+                    Self::#index_name,
+                    (#(#arg_names,)*),
+                )
+            }
+
+            #[doc = #hook_doc]
+            #vis fn #hook_name<F>(
+                instance: *mut Self,
+                f: F,
+            ) -> ::scan::__private::Result<::scan::HookFunction>
+            where
+                F: ::scan::ThunkableClosure<#ret, Self, (#(#arg_types,)*)>,
+            {
+                ::scan::HookFunction::new(instance, 0, Self::#index_name, f)
+            }
+        }
+    });
+
+    Ok(quote! {
+        #(#attrs)*
+        #[repr(C)]
+        #vis struct #ident {
+            #[allow(dead_code)]
+            vtable: *const *const (),
+        }
+
+        impl #ident {
+            #(#methods)*
+        }
+    })
+}
+
+fn parse_method(method: &TraitItemFn, default_index: usize) -> Result<Method> {
+    let sig = &method.sig;
+
+    if let Some(default) = &method.default {
+        return Err(Error::new_spanned(
+            default,
+            "#[vtable] methods cannot have a body",
+        ));
+    }
+
+    if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+        return Err(Error::new_spanned(
+            &sig.generics,
+            "#[vtable] methods cannot be generic",
+        ));
+    }
+
+    if sig.constness.is_some() || sig.asyncness.is_some() || sig.variadic.is_some() {
+        return Err(Error::new_spanned(
+            sig,
+            "#[vtable] methods cannot be const, async or variadic",
+        ));
+    }
+
+    let mut index = default_index;
+    let mut attrs = vec![];
+
+    for attr in &method.attrs {
+        if attr.path().is_ident("index") {
+            index = parse_index(attr)?;
+        } else {
+            attrs.push(attr.clone());
+        }
+    }
+
+    let mut inputs = sig.inputs.iter();
+
+    let receiver = match inputs.next() {
+        Some(FnArg::Receiver(receiver))
+            if receiver.reference.is_some() && receiver.colon_token.is_none() =>
+        {
+            receiver.clone()
+        }
+        _ => {
+            return Err(Error::new(
+                sig.paren_token.span.join(),
+                "#[vtable] methods must take `&self` or `&mut self`",
+            ))
+        }
+    };
+
+    let mut arg_names = vec![];
+    let mut arg_types = vec![];
+
+    for input in inputs {
+        let FnArg::Typed(arg) = input else {
+            unreachable!("receiver can only be the first argument")
+        };
+
+        let Pat::Ident(pat) = &*arg.pat else {
+            return Err(Error::new_spanned(
+                &arg.pat,
+                "#[vtable] method arguments must be plain identifiers",
+            ));
+        };
+
+        arg_names.push(pat.ident.clone());
+        arg_types.push((*arg.ty).clone());
+    }
+
+    let ret = match &sig.output {
+        ReturnType::Default => syn::parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+
+    Ok(Method {
+        attrs,
+        name: sig.ident.clone(),
+        receiver,
+        index,
+        arg_names,
+        arg_types,
+        ret,
+    })
+}
+
+fn parse_index(attr: &Attribute) -> Result<usize> {
+    if let Meta::NameValue(name_value) = &attr.meta {
+        if let Expr::Lit(ExprLit {
+            lit: Lit::Int(index),
+            ..
+        }) = &name_value.value
+        {
+            return index.base10_parse();
+        }
+    }
+
+    Err(Error::new(attr.span(), "expected `#[index = N]`"))
+}
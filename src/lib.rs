@@ -17,6 +17,35 @@ pub use macos::Module;
 
 mod vmthook;
 
+pub use vmthook::abi::{AggregateLayout, AsCraneliftValue, CallConv, CraneliftValue};
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub use vmthook::call::call_virtual_checked;
+pub use vmthook::call::{call_virtual, call_virtual_at, OriginalFunction};
+pub use vmthook::dynamic::{DynamicContext, RawArgs, RawScalar, RawSignature, RawType, RawValue};
+pub use vmthook::registry::{active_hooks, HookInfo, HookStats, ModuleLocation};
+pub use vmthook::set::HookSet;
+pub use vmthook::set_vtable_len;
+pub use vmthook::set_vtable_prefix_len;
 pub use vmthook::thunk::call_original;
 pub use vmthook::thunk::AsCraneliftAbi;
+pub use vmthook::thunk::HookContext;
+pub use vmthook::thunk::ObservableArgs;
+pub use vmthook::thunk::StatefulClosure;
+pub use vmthook::thunk::ThunkableClosure;
+pub use vmthook::thunk::VirtualArgs;
+pub use vmthook::unwind::{set_panic_handler, HookPanic, PanicPolicy};
+pub use vmthook::watchdog::Watchdog;
 pub use vmthook::HookFunction;
+pub use vmthook::HookInstance;
+pub use vmthook::HookOptions;
+pub use vmthook::TableReplaced;
+pub use vmthook::VtableHook;
+
+pub use scan_macros::{vtable, AsCraneliftAbi};
+
+/// Used by code generated from [`vtable`] and [`derive@AsCraneliftAbi`].
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow::Result;
+    pub use cranelift::prelude::AbiParam;
+}
@@ -1,32 +1,512 @@
+pub mod abi;
+pub mod call;
+mod chain;
+pub mod dynamic;
+pub mod registry;
+pub mod set;
 pub mod thunk;
+pub mod unwind;
+pub mod watchdog;
 
 use core::slice;
 use std::{
+    any::Any,
+    cell::RefCell,
     collections::HashMap,
-    sync::{Arc, OnceLock, Weak},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+        Arc, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
 };
 
+use abi::CallConv;
 use anyhow::{bail, Result};
+use chain::HookChain;
+use cranelift_jit::JITModule;
 use parking_lot::{Mutex, MutexGuard};
-use thunk::{ThunkableClosure, TrampolineStorage};
+use registry::HookCounters;
+use thunk::{call_original, HookCode, ObservableArgs, StatefulClosure, ThunkableClosure};
+use unwind::PanicPolicy;
+
+/// How long a retired slot is kept after its last call, for threads that have read the trampoline from
+/// the table but not yet entered the slot, or have left the slot but not yet the trampoline.
+const RETIRE_GRACE: Duration = Duration::from_millis(500);
 
 thread_local! {
-    static GLOBAL_TRAMPOLINE_STORAGE: TrampolineStorage = TrampolineStorage::new().unwrap();
+    // Slots that the current thread is in a call of, innermost last. This can't be used once the
+    // thread's locals are destroyed, and calls from then on, such as from destructors of other thread
+    // locals, aren't tracked.
+    static ENTERED_SLOTS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
 }
 
-#[repr(transparent)]
+/// An instance and the offset of one of its vtable pointers.
 #[derive(PartialEq, Eq, PartialOrd, Hash)]
-struct Instance(*mut ());
+struct Instance(*mut (), usize);
 unsafe impl Send for Instance {}
 unsafe impl Sync for Instance {}
 
+/// What the trampoline of a hooked function calls into. A pointer to it is baked into the trampoline,
+/// so calls go straight to the closure without looking up the instance or taking any locks.
+///
+/// Slots are not dropped directly, but [retired](HookSlot::retire) so that they are only freed when
+/// no calls are using them.
+struct HookSlot {
+    // NOTE(emily): This is not actually a 0 arg function
+    closure: *const dyn Fn(),
+    drop_closure: unsafe fn(*const dyn Fn()),
+    closure_dropped: bool,
+    // The function that is called by call_original, which is the next hook in the chain or the
+    // original function. If the function doesn't use the host's calling convention, this is a bridge
+    // that calls it with its calling convention.
+    original: AtomicPtr<()>,
+    call_conv: CallConv,
+    make_original_bridge: MakeOriginalBridge,
+    trampoline: *const (),
+    // Number of calls that are currently in the trampoline or closure.
+    in_flight: AtomicUsize,
+    // Set when the hook is being removed, after which calls go straight to the original function.
+    closed: AtomicBool,
+    // Whether calls made while the closure is already running on the same thread go to the original
+    // function.
+    skip_reentrant: bool,
+    // What happens when the closure panics.
+    on_panic: PanicPolicy,
+    // The name of the closure's type and the counters of its calls, for the registry.
+    closure_type: &'static str,
+    stats: Option<HookCounters>,
+    // The trampoline and bridges to original functions, which are freed with the slot.
+    code: Mutex<HookCode>,
+    // Identifies the slot in its chain.
+    id: usize,
+    // Where the slot is installed, which is set before it's added to a chain.
+    target: Option<HookTarget>,
+    // Set by HookContext::unhook_after_return.
+    unhook_requested: AtomicBool,
+    // Removes the slot from its chain.
+    unhook: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    // Alive while a shadow table that calls can reach the slot through is, see HookChain::add_copy.
+    pin: Weak<()>,
+}
+
+/// Where a hook is installed, for [`HookContext`].
+#[derive(Clone, Copy, Debug)]
+struct HookTarget {
+    // The hooked instance, or null for vtable hooks.
+    instance: *mut (),
+    index: usize,
+    // The table that other original functions are looked up in, and its length if it's known.
+    original_table: *const *const (),
+    table_len: Option<usize>,
+}
+
+impl std::fmt::Debug for HookSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookSlot")
+            .field("id", &self.id)
+            .field("target", &self.target)
+            .field("original", &self.original)
+            .field("call_conv", &self.call_conv)
+            .field("trampoline", &self.trampoline)
+            .field("in_flight", &self.in_flight)
+            .field("closed", &self.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl Send for HookSlot {}
+unsafe impl Sync for HookSlot {}
+
+/// Drop the box of `F` made by [`ThunkableClosure::into_raw_closure`].
+unsafe fn drop_closure<F>(closure: *const dyn Fn()) {
+    drop(Box::from_raw(closure as *const F as *mut F));
+}
+
+/// Make the bridge to `original` in `module` for the box of `F` made by
+/// [`ThunkableClosure::into_raw_closure`].
+unsafe fn make_original_bridge<R: 'static, T: 'static, Args: 'static, F>(
+    closure: *const dyn Fn(),
+    module: &mut JITModule,
+    call_conv: CallConv,
+    original: *const (),
+) -> Result<Option<*const ()>>
+where
+    F: ThunkableClosure<R, T, Args>,
+{
+    (*(closure as *const F)).make_original_bridge(module, call_conv, original)
+}
+
+static NEXT_SLOT_ID: AtomicUsize = AtomicUsize::new(0);
+
+type MakeOriginalBridge =
+    unsafe fn(*const dyn Fn(), &mut JITModule, CallConv, *const ()) -> Result<Option<*const ()>>;
+
+/// Counts a call as in flight in a slot for as long as it's alive, and whether it's in
+/// `ENTERED_SLOTS`.
+struct InFlight<'a>(&'a HookSlot, bool);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.1 {
+            let _ = ENTERED_SLOTS.try_with(|entered| entered.borrow_mut().pop());
+        }
+
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl HookSlot {
+    /// Make a slot for `f` and a trampoline that calls it, for a function that uses the calling
+    /// convention in `options`. The original function is set by [`HookSlot::set_original`].
+    fn new<R: 'static, T: 'static, Args: 'static, F: ThunkableClosure<R, T, Args>>(
+        f: F,
+        options: HookOptions,
+    ) -> Result<Box<Self>> {
+        // The raw closure is a box of `F`, so it can still be used to make the trampoline.
+        let closure = f.into_raw_closure();
+
+        Self::with_trampoline(
+            closure,
+            drop_closure::<F>,
+            make_original_bridge::<R, T, Args, F>,
+            std::any::type_name::<F>(),
+            options,
+            |module, slot| {
+                let f = unsafe { &*(closure as *const F) };
+                f.make_trampoline(module, slot, options.call_conv)
+            },
+        )
+    }
+
+    /// Make a slot for a raw closure, which is dropped with `drop_closure` and has the type named
+    /// `closure_type`, and the trampoline made by `make_trampoline` from the address of the slot.
+    fn with_trampoline(
+        closure: *const dyn Fn(),
+        drop_closure: unsafe fn(*const dyn Fn()),
+        make_original_bridge: MakeOriginalBridge,
+        closure_type: &'static str,
+        options: HookOptions,
+        make_trampoline: impl FnOnce(&mut JITModule, *const ()) -> Result<*const ()>,
+    ) -> Result<Box<Self>> {
+        let code = HookCode::new()?;
+        let mut slot = Box::new(Self {
+            closure,
+            drop_closure,
+            closure_dropped: false,
+            original: AtomicPtr::new(std::ptr::null_mut()),
+            call_conv: options.call_conv,
+            make_original_bridge,
+            trampoline: std::ptr::null(),
+            in_flight: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            skip_reentrant: options.skip_reentrant,
+            on_panic: options.on_panic,
+            closure_type,
+            stats: options.collect_stats.then(HookCounters::default),
+            code: Mutex::new(code),
+            id: NEXT_SLOT_ID.fetch_add(1, Ordering::Relaxed),
+            target: None,
+            unhook_requested: AtomicBool::new(false),
+            unhook: None,
+            pin: Weak::new(),
+        });
+
+        let address = &*slot as *const Self as *const ();
+        slot.trampoline = slot
+            .code
+            .get_mut()
+            .define(|module| make_trampoline(module, address))?;
+
+        Ok(slot)
+    }
+
+    /// Set the function that is called by [`crate::call_original`].
+    fn set_original(&self, original: *const ()) -> Result<()> {
+        let bridge = self
+            .code
+            .lock()
+            .original_bridge(original, |module| unsafe {
+                (self.make_original_bridge)(self.closure, module, self.call_conv, original)
+            })?;
+
+        self.original
+            .store(bridge.unwrap_or(original) as *mut (), Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Count a call as in flight until the returned guard is dropped.
+    fn enter(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        let entered = ENTERED_SLOTS
+            .try_with(|entered| entered.borrow_mut().push(self as *const Self as usize))
+            .is_ok();
+
+        InFlight(self, entered)
+    }
+
+    /// Call the closure with `call`, counting the call if the slot collects stats.
+    fn timed<R>(&self, call: impl FnOnce() -> R) -> R {
+        let Some(stats) = &self.stats else {
+            return call();
+        };
+
+        let start = Instant::now();
+        let ret = call();
+        stats.record(start.elapsed());
+
+        ret
+    }
+
+    /// Where the slot is installed.
+    fn target(&self) -> &HookTarget {
+        self.target
+            .as_ref()
+            .expect("slots are only called once they are installed")
+    }
+
+    /// The original function at `index` of the table the slot is installed in, looking past any
+    /// [`VtableHook`]s of it.
+    fn original_function(&self, index: usize) -> Option<*const ()> {
+        let target = self.target();
+
+        if target.table_len.is_some_and(|len| index >= len) {
+            return None;
+        }
+
+        let entry = target.original_table.wrapping_add(index);
+        let original = VtableHook::chains()
+            .get(&(entry as usize))
+            .map(HookChain::original)
+            .unwrap_or_else(|| unsafe { *entry });
+
+        Some(original)
+    }
+
+    /// Remove the slot from its chain if [`HookContext::unhook_after_return`] was called. This is
+    /// called by the thunk after the closure returns.
+    fn unhook_if_requested(&self) {
+        if self.unhook_requested.swap(false, Ordering::SeqCst) {
+            if let Some(unhook) = &self.unhook {
+                unhook(self.id);
+            }
+        }
+    }
+
+    /// Whether the slot has been closed, and calls should go to the original function.
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Whether the call that is being made should skip the closure and go to the original function,
+    /// because the slot is closed or the call is reentrant and those are skipped.
+    fn passes_through(&self) -> bool {
+        self.is_closed() || (self.skip_reentrant && self.depth() > 1)
+    }
+
+    /// The slot of the innermost call of a hook that is in progress on this thread.
+    fn innermost() -> Option<*const HookSlot> {
+        ENTERED_SLOTS
+            .try_with(|entered| entered.borrow().last().copied())
+            .ok()
+            .flatten()
+            .map(|slot| slot as *const HookSlot)
+    }
+
+    /// The number of calls of the slot that are in progress on this thread, which is 0 once the
+    /// thread's locals are destroyed.
+    fn depth(&self) -> usize {
+        ENTERED_SLOTS
+            .try_with(|entered| {
+                entered
+                    .borrow()
+                    .iter()
+                    .filter(|slot| **slot == self as *const Self as usize)
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Whether calls on other threads are in the slot. Calls on this thread are not counted, as they
+    /// can't finish while this thread is waiting for them.
+    fn in_flight_on_other_threads(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) > self.depth()
+    }
+
+    /// Send new calls to the original function instead of the closure, and return whether there are
+    /// no calls in the closure on other threads.
+    fn close(&self) -> bool {
+        self.closed.store(true, Ordering::SeqCst);
+        !self.in_flight_on_other_threads()
+    }
+
+    /// Undo [`HookSlot::close`].
+    fn reopen(&self) {
+        self.closed.store(false, Ordering::SeqCst);
+    }
+
+    /// Close the slot and wait until there are no calls in the closure on other threads.
+    fn close_and_wait(&self) {
+        while !self.close() {
+            std::thread::yield_now();
+        }
+    }
+
+    fn drop_closure(&mut self) {
+        if !self.closure_dropped {
+            self.closure_dropped = true;
+            unsafe { (self.drop_closure)(self.closure) };
+        }
+    }
+
+    /// Free the slot once it is no longer in the table and has no calls in flight.
+    ///
+    /// If there are no calls in flight, the closure is dropped straight away, and only the trampoline
+    /// is kept around for a while.
+    fn retire(mut self: Box<Self>) {
+        let idle = self.in_flight.load(Ordering::SeqCst) == 0;
+
+        if self.is_closed() && idle {
+            self.drop_closure();
+        }
+
+        let retired_slot = Retired::Slot(self);
+        let idle_since = (!retired_slot.in_use()).then(Instant::now);
+
+        retired().push((idle_since, retired_slot));
+        free_retired();
+    }
+}
+
+impl Drop for HookSlot {
+    fn drop(&mut self) {
+        self.drop_closure();
+    }
+}
+
+/// Something that was taken out of use, which is freed once no calls can be using it, see
+/// [`HookSlot::retire`].
+enum Retired {
+    Slot(Box<HookSlot>),
+    // The shadow table of an instance. Calls aren't counted in it, as a call could have read the
+    // vtable pointer and not yet the function from the table.
+    Table(#[allow(dead_code)] Box<[*const ()]>),
+}
+
+// The table is only kept to be freed.
+unsafe impl Send for Retired {}
+
+impl Retired {
+    /// Whether calls are still using it.
+    fn in_use(&self) -> bool {
+        match self {
+            Retired::Slot(slot) => {
+                slot.in_flight.load(Ordering::SeqCst) != 0 || slot.pin.strong_count() != 0
+            }
+            Retired::Table(_) => false,
+        }
+    }
+}
+
+/// Something retired and when it was first seen not in use, which is `None` until then.
+type RetiredEntry = (Option<Instant>, Retired);
+
+fn retired() -> MutexGuard<'static, Vec<RetiredEntry>> {
+    static RETIRED: OnceLock<Mutex<Vec<RetiredEntry>>> = OnceLock::new();
+
+    RETIRED.get_or_init(Default::default).lock()
+}
+
+/// Free what was retired and hasn't been in use for [`RETIRE_GRACE`].
+///
+/// The grace period starts when it is first seen not in use rather than when it was retired, as a call
+/// that has just left the thunk can still be in the trampoline.
+fn free_retired() {
+    let mut retired = retired();
+
+    for (idle_since, retired) in retired.iter_mut() {
+        if retired.in_use() {
+            *idle_since = None;
+        } else if idle_since.is_none() {
+            *idle_since = Some(Instant::now());
+        }
+    }
+
+    let (free, keep) = std::mem::take(&mut *retired)
+        .into_iter()
+        .partition(|(idle_since, _)| {
+            idle_since.is_some_and(|idle_since| idle_since.elapsed() >= RETIRE_GRACE)
+        });
+
+    *retired = keep;
+    drop(retired);
+
+    // Dropping closures can drop other hooks, so this has to happen after unlocking.
+    drop::<Vec<_>>(free);
+}
+
+/// Number of entries before the functions of a vtable, which are copied into shadow tables so that RTTI
+/// and `dynamic_cast` keep working on hooked instances.
+///
+/// On MSVC this is the complete object locator at `[-1]`, on Itanium it's `offset_to_top` at `[-2]`
+/// and `typeinfo` at `[-1]`. Virtual base and vcall offsets of classes with virtual bases come before
+/// these, and are only copied for vtables given a longer prefix with [`set_vtable_prefix_len`].
+#[cfg(target_env = "msvc")]
+const TABLE_PREFIX_LEN: usize = 1;
+#[cfg(not(target_env = "msvc"))]
+const TABLE_PREFIX_LEN: usize = 2;
+
+/// The vtable pointer of a hooked instance was found pointing somewhere other than its shadow table,
+/// because something other than this crate replaced it.
+///
+/// This is returned as the error when hooking or checking such an instance, and passed to the
+/// handler set with [`HookInstance::set_replaced_handler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableReplaced {
+    pub instance: *mut (),
+    pub offset: usize,
+    /// The shadow table that the instance should be pointing at.
+    pub expected: *const *const (),
+    /// The table that the instance is pointing at instead.
+    pub found: *const *const (),
+}
+
+unsafe impl Send for TableReplaced {}
+unsafe impl Sync for TableReplaced {}
+
+impl std::fmt::Display for TableReplaced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the vtable pointer of instance {:?} at offset {} was replaced with {:?}, expected {:?}",
+            self.instance, self.offset, self.found, self.expected
+        )
+    }
+}
+
+impl std::error::Error for TableReplaced {}
+
+type ReplacedHandler = Arc<dyn Fn(&TableReplaced) + Send + Sync>;
+
+/// The shadow table of a hooked instance, which is shared by the [`HookFunction`]s of that instance.
 #[derive(Debug)]
-struct HookInstance {
+pub struct HookInstance {
     instance: *mut (),
+    // Offset of the vtable pointer from instance, which is not 0 for secondary bases.
+    offset: usize,
     original_table: *const *const (),
+    // Copy of the original table starting from its prefix, see TABLE_PREFIX_LEN.
     new_table: Box<[*const ()]>,
-    // NOTE(emily): These are not actually 0 arg functions
-    closures: HashMap<usize, *const dyn Fn()>,
+    // Number of entries of new_table before the functions.
+    prefix_len: usize,
+    chains: HashMap<usize, HookChain>,
+    // The indices of the functions that were hooked by a VtableHook when the table was copied, which
+    // it keeps up to date in new_table, see HookChain::add_copy.
+    vtable_copies: Vec<(usize, Arc<()>)>,
+    // Set when the instance may no longer exist, after which it is never written to.
+    forgotten: bool,
 }
 
 impl HookInstance {
@@ -40,114 +520,410 @@ impl HookInstance {
         hooked_instances
     }
 
-    fn for_instance(instance: *mut ()) -> Arc<Mutex<HookInstance>> {
+    fn for_instance(instance: *mut (), offset: usize) -> Arc<Mutex<HookInstance>> {
         let mut hooked_instances = HookInstance::all_hooked_instances();
 
         if let Some(hook) = hooked_instances
-            .get(&Instance(instance))
+            .get(&Instance(instance, offset))
             .and_then(|hook| hook.upgrade())
         {
             return hook;
         }
 
-        let hook_instance = Arc::new(Mutex::new(HookInstance::new(instance)));
+        let hook_instance = Arc::new(Mutex::new(HookInstance::new(instance, offset)));
 
-        hooked_instances.insert(Instance(instance), Arc::downgrade(&hook_instance));
+        hooked_instances.insert(Instance(instance, offset), Arc::downgrade(&hook_instance));
 
         hook_instance
     }
 
+    /// Every hooked instance that is still alive.
+    fn hooked_instances() -> Vec<Arc<Mutex<HookInstance>>> {
+        Self::all_hooked_instances()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
+
+    fn replaced_handler() -> MutexGuard<'static, Option<ReplacedHandler>> {
+        static REPLACED_HANDLER: OnceLock<Mutex<Option<ReplacedHandler>>> = OnceLock::new();
+
+        REPLACED_HANDLER.get_or_init(Default::default).lock()
+    }
+
+    /// Call `handler` when an instance is found to have had its vtable pointer replaced while it is
+    /// being unhooked or by a [`watchdog::Watchdog`], replacing the previous handler.
+    ///
+    /// When that happens while unhooking, the vtable pointer is left alone rather than putting the
+    /// original table back, so that whatever replaced it keeps working. The shadow table and the hooks
+    /// in it are never freed either, in case it kept pointers into them, and the hooks pass calls
+    /// straight through to the original functions.
+    pub fn set_replaced_handler(handler: impl Fn(&TableReplaced) + Send + Sync + 'static) {
+        *Self::replaced_handler() = Some(Arc::new(handler));
+    }
+
+    /// Call the handler set with [`HookInstance::set_replaced_handler`].
+    fn report_replaced(replaced: &TableReplaced) {
+        // The handler could set another handler, so don't keep it locked.
+        let handler = Self::replaced_handler().clone();
+
+        if let Some(handler) = handler {
+            handler(replaced);
+        }
+    }
+
+    /// Check that the vtable pointer of `instance`, `offset` bytes from it, still points at its shadow
+    /// table. Instances that aren't hooked are fine.
+    ///
+    /// The error is a [`TableReplaced`] when the pointer was replaced.
+    pub fn check(instance: *mut impl Sized, offset: usize) -> Result<()> {
+        let hook = Self::all_hooked_instances()
+            .get(&Instance(instance as *mut (), offset))
+            .and_then(Weak::upgrade);
+
+        match hook {
+            Some(hook) => hook.lock().check_table(),
+            None => Ok(()),
+        }
+    }
+
+    /// Point the vtable pointer of `instance`, `offset` bytes from it, back at its shadow table if it
+    /// was replaced, and return whether it was.
+    ///
+    /// This undoes whatever replaced it, so only do this for replacements that would otherwise undo
+    /// the hooks of the instance, like a class resetting its own vtable pointer.
+    pub fn rehook(instance: *mut impl Sized, offset: usize) -> bool {
+        let hook = Self::all_hooked_instances()
+            .get(&Instance(instance as *mut (), offset))
+            .and_then(Weak::upgrade);
+
+        hook.is_some_and(|hook| hook.lock().rehook_table().is_some())
+    }
+
+    fn vtable_lens() -> MutexGuard<'static, HashMap<usize, usize>> {
+        static VTABLE_LENS: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
+
+        VTABLE_LENS.get_or_init(Default::default).lock()
+    }
+
+    fn vtable_prefix_lens() -> MutexGuard<'static, HashMap<usize, usize>> {
+        static VTABLE_PREFIX_LENS: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
+
+        VTABLE_PREFIX_LENS.get_or_init(Default::default).lock()
+    }
+
+    /// Count the functions in `table`, unless its length was given with [`set_vtable_len`].
+    ///
+    /// Vtables are not terminated, so the table ends at the first entry that doesn't point into
+    /// executable memory. For a vtable in a loaded module this is usually the RTTI of the next
+    /// vtable (the complete object locator on MSVC, `offset_to_top` and `typeinfo` on Itanium), or
+    /// the end of the section.
     unsafe fn count_funcs(table: *const *const ()) -> usize {
+        if let Some(len) = Self::vtable_lens().get(&(table as usize)) {
+            return *len;
+        }
+
+        let mut readable = 0..0;
+        let mut executable: Vec<Range<usize>> = vec![];
+
         for i in 0.. {
-            if (*table.wrapping_add(i)).is_null() {
-                return i;
+            let entry = table.wrapping_add(i);
+
+            // Make sure that the entry can be read before reading it, as the table might be at the
+            // end of a section.
+            if !readable.contains(&(entry as usize)) {
+                match region::query(entry) {
+                    Ok(region) if region.is_readable() => readable = region.as_range(),
+                    _ => return i,
+                }
+            }
+
+            let function = *entry as usize;
+
+            if !executable.iter().any(|range| range.contains(&function)) {
+                match region::query(function as *const u8) {
+                    Ok(region) if region.is_executable() => executable.push(region.as_range()),
+                    _ => return i,
+                }
             }
         }
+
         unreachable!("table must end at some point")
     }
 
-    unsafe fn get_table(instance: *const ()) -> *const *const () {
-        *(instance as *const *const *const ())
+    unsafe fn get_table(instance: *const (), offset: usize) -> *const *const () {
+        *((instance as *const u8).add(offset) as *const *const *const ())
     }
 
     #[allow(dead_code)]
-    unsafe fn get_table_mut(instance: *mut ()) -> *mut *const () {
-        *(instance as *const *mut *const ())
+    unsafe fn get_table_mut(instance: *mut (), offset: usize) -> *mut *const () {
+        *((instance as *const u8).add(offset) as *const *mut *const ())
     }
 
-    unsafe fn replace_table_pointer(instance: *mut (), new_table: *const *const ()) {
-        let pointer_to_table = instance as *mut *const *const ();
+    unsafe fn replace_table_pointer(instance: *mut (), offset: usize, new_table: *const *const ()) {
+        let pointer_to_table = (instance as *mut u8).add(offset) as *mut *const *const ();
         *pointer_to_table = new_table;
     }
 
-    pub fn new(instance: *mut ()) -> Self {
-        let original_table = unsafe { Self::get_table(instance) };
+    fn new(instance: *mut (), offset: usize) -> Self {
+        let original_table = unsafe { Self::get_table(instance, offset) };
+
+        // Keep the vtable hooks from changing while the table is copied, as the copy is only kept
+        // up to date once it's added to their chains.
+        let mut vtable_chains = VtableHook::chains();
 
         let table_len = unsafe { Self::count_funcs(original_table) };
-        let new_table: Box<_> = unsafe { slice::from_raw_parts(original_table, table_len) }
-            .to_vec()
-            .into_boxed_slice();
+        let prefix_len = Self::vtable_prefix_lens()
+            .get(&(original_table as usize))
+            .copied()
+            .unwrap_or(TABLE_PREFIX_LEN);
+
+        // Tables that were not made by a compiler might not have a prefix, so leave it null if it
+        // can't be read.
+        let prefix = original_table.wrapping_sub(prefix_len);
+        let prefix_readable = (0..prefix_len).all(|i| {
+            region::query(prefix.wrapping_add(i)).is_ok_and(|region| region.is_readable())
+        });
+
+        let mut new_table: Box<_> = if prefix_readable {
+            unsafe { slice::from_raw_parts(prefix, prefix_len) }.to_vec()
+        } else {
+            vec![std::ptr::null(); prefix_len]
+        }
+        .into_iter()
+        .chain(
+            unsafe { slice::from_raw_parts(original_table, table_len) }
+                .iter()
+                .copied(),
+        )
+        .collect();
 
-        unsafe { Self::replace_table_pointer(instance, new_table.as_ptr()) };
+        let functions = new_table.as_mut_ptr().wrapping_add(prefix_len);
+        let vtable_copies = (0..table_len)
+            .filter_map(|index| {
+                let chain =
+                    vtable_chains.get_mut(&(original_table.wrapping_add(index) as usize))?;
+                Some((index, unsafe {
+                    chain.add_copy(functions.wrapping_add(index))
+                }))
+            })
+            .collect();
+        drop(vtable_chains);
+
+        unsafe { Self::replace_table_pointer(instance, offset, functions) };
 
         Self {
             original_table,
             instance,
+            offset,
             new_table,
-            closures: Default::default(),
+            prefix_len,
+            chains: Default::default(),
+            vtable_copies,
+            forgotten: false,
         }
     }
 
-    pub fn closure(&self, index: usize) -> *const dyn Fn() {
-        self.closures[&index]
-    }
+    /// Stop using `instance`, for when it is about to be destroyed or has been already.
+    ///
+    /// Its vtable pointer is left pointing at the shadow table, which stays alive until the hooks of
+    /// the instance are dropped, so that nothing is written to the instance after this. The hooks of
+    /// the instance stay in the shadow table, and an instance that is later made at the same address
+    /// is hooked from scratch.
+    ///
+    /// This is for instances whose lifetime is managed somewhere else, see
+    /// [`HookInstance::track_destructor`] to do this automatically.
+    pub fn forget(instance: *mut impl Sized) {
+        let instance = instance as *mut ();
 
-    pub fn original_function(&self, index: usize) -> *const () {
-        unsafe { *self.original_table.wrapping_add(index) }
+        let mut forgotten = vec![];
+        Self::all_hooked_instances().retain(|key, hook| {
+            if key.0 == instance {
+                forgotten.push(hook.clone());
+            }
+            key.0 != instance
+        });
+
+        for hook in forgotten {
+            if let Some(hook) = hook.upgrade() {
+                hook.lock().forgotten = true;
+            }
+        }
     }
 
-    pub fn hook_function(&mut self, index: usize, f: *const ()) -> Result<()> {
-        ensure_range(index, self.new_table.len())?;
-        self.new_table[index] = f;
+    /// Hook the virtual destructor of `instance` at `index` of the vtable that is `offset` bytes from
+    /// it, so that its hooks are torn down before the original destructor runs.
+    ///
+    /// The destructor puts the original vtable pointer back, unhooks every function of the instance
+    /// and forgets it like [`HookInstance::forget`]. The [`HookFunction`]s of the instance can still
+    /// be dropped afterwards, which does nothing.
+    ///
+    /// On MSVC `index` is the scalar deleting destructor, which is usually 0. On other targets it's
+    /// either the complete object destructor or the deleting destructor that follows it, which are
+    /// usually 0 and 1, and only destroying the instance through that one is tracked.
+    ///
+    /// The instance must already have been hooked by a [`HookFunction`], and tracking lasts until the
+    /// instance is destroyed or all of its hooks are dropped.
+    pub fn track_destructor(instance: *mut impl Sized, offset: usize, index: usize) -> Result<()> {
+        let key = Instance(instance as *mut (), offset);
+
+        let Some(hook) = Self::all_hooked_instances()
+            .get(&key)
+            .and_then(|hook| hook.upgrade())
+        else {
+            bail!("instance {instance:?} with offset {offset} isn't hooked");
+        };
+
+        let weak = Arc::downgrade(&hook);
+        let tear_down = move || {
+            let Some(hook) = weak.upgrade() else {
+                return;
+            };
+
+            let mut hooked_instances = Self::all_hooked_instances();
+            if hooked_instances
+                .get(&key)
+                .is_some_and(|hooked| hooked.ptr_eq(&weak))
+            {
+                hooked_instances.remove(&key);
+            }
+            drop(hooked_instances);
+
+            let mut hook = hook.lock();
+            if hook.forgotten {
+                return;
+            }
+
+            unsafe { Self::replace_table_pointer(hook.instance, hook.offset, hook.original_table) };
+            hook.forgotten = true;
+
+            // Dropping the chains can drop closures that own other hooks, so do it after unlocking.
+            let chains = std::mem::take(&mut hook.chains);
+            drop(hook);
+            drop(chains);
+        };
+
+        // The scalar deleting destructor takes flags for whether to free the instance, and returns it.
+        #[cfg(target_env = "msvc")]
+        let destructor = move |ctx, this: &mut u8, flags: u32| -> *mut () {
+            tear_down();
+            call_original(ctx, this, (flags,))
+        };
+        // The return type can't be inferred from `call_original`, so it has to be written out.
+        #[cfg(not(target_env = "msvc"))]
+        #[allow(clippy::unused_unit)]
+        let destructor = move |ctx, this: &mut u8| -> () {
+            tear_down();
+            call_original(ctx, this, ())
+        };
+
+        // Go after every other hook of the destructor, so that they still run.
+        hook.lock().hook_function_with_slot(index, i32::MIN, || {
+            HookSlot::new(destructor, HookOptions::default())
+        })?;
+
         Ok(())
     }
 
-    pub fn unhook_function(&mut self, index: usize) -> Result<()> {
-        ensure_range(index, self.new_table.len())?;
-        self.new_table[index] = unsafe { *self.original_table.wrapping_add(index) };
-        Ok(())
+    /// The shadow table that the instance points at while it's hooked.
+    fn shadow_table(&self) -> *const *const () {
+        self.new_table.as_ptr().wrapping_add(self.prefix_len)
+    }
+
+    /// Check whether the vtable pointer of the instance has been replaced.
+    fn replaced(&self) -> Option<TableReplaced> {
+        if self.forgotten {
+            return None;
+        }
+
+        let found = unsafe { Self::get_table(self.instance, self.offset) };
+        let expected = self.shadow_table();
+
+        (found != expected).then_some(TableReplaced {
+            instance: self.instance,
+            offset: self.offset,
+            expected,
+            found,
+        })
     }
 
-    fn hook_function_with_closure<R: 'static, T: 'static, Args: 'static>(
+    /// Remove hook `id` of function `index`, see [`unhook_from_chain`].
+    ///
+    /// If the table was replaced, the hook is only closed and stays in the chain so that it is never
+    /// freed, see [`HookInstance`]'s `Drop`.
+    fn unhook(&mut self, index: usize, id: usize, wait: bool) -> Option<Option<Box<HookSlot>>> {
+        if self.replaced().is_some() {
+            if let Some(slot) = self.chains.get(&index).and_then(|chain| chain.slot(id)) {
+                slot.close();
+            }
+
+            return Some(None);
+        }
+
+        unhook_from_chain(&mut self.chains, index, id, wait)
+    }
+
+    fn check_table(&self) -> Result<()> {
+        match self.replaced() {
+            Some(replaced) => Err(replaced.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Point the instance back at the shadow table if it was replaced, and return what it was
+    /// replaced with.
+    fn rehook_table(&mut self) -> Option<TableReplaced> {
+        let replaced = self.replaced()?;
+
+        unsafe { Self::replace_table_pointer(self.instance, self.offset, self.shadow_table()) };
+
+        Some(replaced)
+    }
+
+    /// The functions of the shadow table, without its prefix.
+    fn functions(&mut self) -> &mut [*const ()] {
+        &mut self.new_table[self.prefix_len..]
+    }
+
+    /// Hook function `index` with the slot made by `make_slot`, and return the id of the hook in the
+    /// chain of that function.
+    fn hook_function_with_slot(
         &mut self,
         index: usize,
-        f: impl ThunkableClosure<R, T, Args>,
-    ) -> Result<()> {
-        let trampoline = GLOBAL_TRAMPOLINE_STORAGE.with(|thunk_storage| {
-            let mut module = thunk_storage.module();
-            f.make_trampoline(&mut module, index)
-        })?;
+        priority: i32,
+        make_slot: impl FnOnce() -> Result<Box<HookSlot>>,
+    ) -> Result<usize> {
+        ensure_range(index, self.functions().len())?;
+        self.check_table()?;
 
-        let closure = f.into_raw_closure();
+        let mut slot = make_slot()?;
+        slot.target = Some(HookTarget {
+            instance: self.instance,
+            index,
+            original_table: self.original_table,
+            table_len: Some(self.functions().len()),
+        });
 
-        self.hook_function(index, trampoline)?;
-        self.closures.insert(index, closure);
+        let entry = &mut self.functions()[index] as *mut *const ();
 
-        Ok(())
-    }
+        let chain = self
+            .chains
+            .entry(index)
+            .or_insert_with(|| unsafe { HookChain::new(entry, slot.call_conv) });
 
-    fn unhook_function_with_closure(&mut self, index: usize) -> Result<()> {
-        self.unhook_function(index)?;
+        let id = chain.insert(priority, slot);
 
-        self.closures.remove(&index);
+        if chain.is_empty() {
+            self.chains.remove(&index);
+        }
 
-        Ok(())
+        id
     }
 }
 
-fn ensure_range(index: usize, max: usize) -> Result<()> {
-    if index > max {
-        bail!("index {index} is outside the table len {max}");
+fn ensure_range(index: usize, len: usize) -> Result<()> {
+    if index >= len {
+        bail!("index {index} is outside the table len {len}");
     }
 
     Ok(())
@@ -155,31 +931,133 @@ fn ensure_range(index: usize, max: usize) -> Result<()> {
 
 impl Drop for HookInstance {
     fn drop(&mut self) {
-        unsafe { Self::replace_table_pointer(self.instance, self.original_table) };
+        if let Some(replaced) = self.replaced() {
+            Self::report_replaced(&replaced);
+
+            // Whatever replaced the table may have copied the shadow table or kept it as its
+            // original, and still call into it and the trampolines in it. Leave them all alive, with
+            // the hooks passing calls straight through.
+            for (_, chain) in self.chains.drain() {
+                chain.leak();
+            }
+            Box::leak(std::mem::take(&mut self.new_table));
+            std::mem::forget(std::mem::take(&mut self.vtable_copies));
+
+            return;
+        }
+
+        if !self.forgotten {
+            unsafe { Self::replace_table_pointer(self.instance, self.offset, self.original_table) };
+        }
+
+        // The chains put the original functions back in the new table, so drop them before it.
+        self.chains.clear();
+
+        let mut vtable_chains = VtableHook::chains();
+        for (index, _) in &self.vtable_copies {
+            let entry = self.original_table.wrapping_add(*index) as usize;
+
+            if let Some(chain) = vtable_chains.get_mut(&entry) {
+                chain.remove_copy(&mut self.new_table[self.prefix_len + index]);
+            }
+        }
+        drop(vtable_chains);
+
+        // The hooks of the vtable can be freed once the table is, which is after the grace period.
+        self.vtable_copies.clear();
+
+        // Calls could have read the vtable pointer before it was put back, and not yet the function.
+        retired().push((
+            Some(Instant::now()),
+            Retired::Table(std::mem::take(&mut self.new_table)),
+        ));
+        free_retired();
     }
 }
 
 unsafe impl Send for HookInstance {}
 unsafe impl Sync for HookInstance {}
 
+/// Set the number of functions in `vtable`, for when it can't be worked out from the vtable itself.
+///
+/// This is used for the copies of `vtable` made by [`HookFunction`]s, and only affects instances that
+/// haven't been hooked yet.
+pub fn set_vtable_len(vtable: *const *const (), len: usize) {
+    HookInstance::vtable_lens().insert(vtable as usize, len);
+}
+
+/// Set the number of entries before the functions of `vtable` that are copied into shadow tables,
+/// which is 1 on MSVC and 2 on other targets by default.
+///
+/// On Itanium, classes with virtual bases have virtual base and vcall offsets before `offset_to_top`,
+/// which code using a hooked instance reads through its vtable pointer, so `len` should cover them.
+/// Like [`set_vtable_len`], this only affects instances that haven't been hooked yet.
+pub fn set_vtable_prefix_len(vtable: *const *const (), len: usize) {
+    HookInstance::vtable_prefix_lens().insert(vtable as usize, len);
+}
+
+/// Options for how a function is hooked, for [`HookFunction::with_options`] and
+/// [`VtableHook::with_options`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HookOptions {
+    /// The calling convention of the function, see [`HookFunction::with_call_conv`].
+    pub call_conv: CallConv,
+    /// Where the hook goes when the function is hooked more than once. Hooks with a higher priority
+    /// are called first, and hooks with the same priority are called newest first.
+    pub priority: i32,
+    /// Send calls that are made while the closure is already running on the same thread, such as
+    /// from something the closure calls, to the original function instead of the closure again.
+    pub skip_reentrant: bool,
+    /// What happens when the closure panics, since the panic can't unwind into the caller of the
+    /// function.
+    pub on_panic: PanicPolicy,
+    /// Count the calls of the closure and the time spent in them, which are listed by
+    /// [`registry::active_hooks`].
+    pub collect_stats: bool,
+}
+
 /// A hooked function in an instance's vtable.
+///
+/// A function can be hooked more than once. The hooks form a chain, ordered by
+/// [`HookOptions::priority`], where [`crate::call_original`] calls the next hook and the last hook
+/// calls the original function. Unhooking any of them leaves the rest of the chain in place.
+///
+/// Unhooking waits for calls that are in the closure, but a call can have read the trampoline from
+/// the vtable and not yet reached the closure. The trampoline, and the copy of the vtable once the
+/// last hook of the instance is gone, are kept for half a second after the last call for those. A
+/// thread that is stopped in between for longer than that, such as by a debugger, still ends up in
+/// freed memory when it carries on.
 #[derive(Debug)]
 pub struct HookFunction {
     instance_hook: Arc<Mutex<HookInstance>>,
     index: usize,
+    id: usize,
+    // The state of a hook made with HookFunction::with_state, which is a Mutex of it.
+    state: Option<Arc<dyn Any + Send + Sync>>,
 }
 
 impl HookFunction {
-    /// Hook a function in an instances vtable, providing a closure that will be called instead of the
-    /// original.
+    /// Hook a function in the vtable that is `offset` bytes from `instance`, providing a closure that
+    /// will be called instead of the original.
     /// The closure provided should take a `ctx`, `&mut this` and arguments of the function being called.
     ///
+    /// `offset` is 0 unless the function belongs to a secondary base of a class with multiple
+    /// inheritance. In that case `this` is `instance + offset`, as the original function expects.
+    ///
     /// When the [`HookFunction`] is dropped, the function will be un-hooked.
     ///
     ///
     /// All arguments of the function are expected to be able to be made into
     /// [CraneLift basic types](https://docs.rs/cranelift-codegen/latest/cranelift_codegen/ir/types/index.html).
-    /// If you need to do this for a custom type, then consider implementing [`crate::AsCraneliftAbi`] for it.
+    /// If you need to do this for a custom type, then consider implementing [`crate::AsCraneliftAbi`] for it,
+    /// or deriving it for `#[repr(transparent)]` newtypes.
+    /// `#[repr(C)]` structs that are passed or returned by value can be described with
+    /// [`crate::cranelift_aggregate`], and are moved into the registers, stack slots or hidden return
+    /// pointer that the platform ABI expects.
+    ///
+    /// Functions that return `void` are hooked with a closure returning `()`. The return type can't be
+    /// inferred from the call to [`crate::call_original`], so write it out as `-> ()`, and allow
+    /// `clippy::unused_unit` where the closure is made if you use clippy.
     ///
     ///
     /// # Example
@@ -187,8 +1065,9 @@ impl HookFunction {
     /// ```rs
     ///  let instance_function_hook = HookFunction::new(
     ///      instance,
+    ///      0,
     ///      10,
-    ///      |ctx, this, arg: usize| -> usize {
+    ///      |ctx, this: &mut Entity, arg: usize| -> usize {
     ///         let arg = arg + 10
     ///         call_original(ctx, this, (arg,))
     ///     },
@@ -197,26 +1076,624 @@ impl HookFunction {
     /// ```
     ///
     pub fn new<R: 'static, T: 'static, Args: 'static>(
-        instance: *mut T,
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        f: impl ThunkableClosure<R, T, Args>,
+    ) -> Result<Self> {
+        Self::with_options(instance, offset, index, HookOptions::default(), f)
+    }
+
+    /// Like [`HookFunction::new`], but for a function that uses the calling convention `call_conv`
+    /// rather than the platform default.
+    ///
+    /// The closure is still called like any other rust function, and [`crate::call_original`] calls
+    /// the original function with `call_conv`.
+    ///
+    pub fn with_call_conv<R: 'static, T: 'static, Args: 'static>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        call_conv: CallConv,
+        f: impl ThunkableClosure<R, T, Args>,
+    ) -> Result<Self> {
+        let options = HookOptions {
+            call_conv,
+            ..Default::default()
+        };
+
+        Self::with_options(instance, offset, index, options, f)
+    }
+
+    /// Like [`HookFunction::new`], with the [`HookOptions`] in `options`.
+    ///
+    /// Every hook of a function must use the same calling convention.
+    ///
+    pub fn with_options<R: 'static, T: 'static, Args: 'static>(
+        instance: *mut impl Sized,
+        offset: usize,
         index: usize,
+        options: HookOptions,
         f: impl ThunkableClosure<R, T, Args>,
     ) -> Result<Self> {
-        let instance_hook = HookInstance::for_instance(instance as *mut ());
+        Self::with_slot(instance, offset, index, options.priority, || {
+            HookSlot::new(f, options)
+        })
+    }
+
+    /// Hook a function with a closure that is called with `this` and the arguments before the original
+    /// function, which is always called afterwards. The closure can change the arguments that the
+    /// original function is called with.
+    ///
+    /// The return type of the function can't be inferred from the closure, so it has to be given.
+    ///
+    /// ```rs
+    /// let hook = HookFunction::before::<usize, _, _>(
+    ///     instance,
+    ///     0,
+    ///     10,
+    ///     |this: &mut Entity, args: &mut (usize,)| args.0 += 10,
+    /// )?;
+    /// ```
+    ///
+    pub fn before<R: 'static, T: 'static, Args: ObservableArgs<R, T>>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        f: impl Fn(&mut T, &mut Args) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        Self::before_with_options(instance, offset, index, HookOptions::default(), f)
+    }
+
+    /// Like [`HookFunction::before`], with the [`HookOptions`] in `options`.
+    pub fn before_with_options<R: 'static, T: 'static, Args: ObservableArgs<R, T>>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        options: HookOptions,
+        f: impl Fn(&mut T, &mut Args) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        // List the hook under the type of `f` rather than the closure that wraps it.
+        let closure_type = std::any::type_name_of_val(&f);
+
+        Self::with_slot(instance, offset, index, options.priority, || {
+            let mut slot = HookSlot::new(Args::before(f), options)?;
+            slot.closure_type = closure_type;
 
-        instance_hook.lock().hook_function_with_closure(index, f)?;
+            Ok(slot)
+        })
+    }
+
+    /// Hook a function with a closure that is called with `this`, the arguments and the return value
+    /// after the original function, which is always called first. The closure can change the return
+    /// value.
+    ///
+    /// The closure is given a clone of the arguments that the original function was called with.
+    ///
+    /// ```rs
+    /// let hook = HookFunction::after(
+    ///     instance,
+    ///     0,
+    ///     10,
+    ///     |this: &mut Entity, args: &(usize,), ret: &mut usize| *ret += args.0,
+    /// )?;
+    /// ```
+    ///
+    pub fn after<R: 'static, T: 'static, Args: ObservableArgs<R, T> + Clone>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        f: impl Fn(&mut T, &Args, &mut R) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        Self::after_with_options(instance, offset, index, HookOptions::default(), f)
+    }
+
+    /// Like [`HookFunction::after`], with the [`HookOptions`] in `options`.
+    pub fn after_with_options<R: 'static, T: 'static, Args: ObservableArgs<R, T> + Clone>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        options: HookOptions,
+        f: impl Fn(&mut T, &Args, &mut R) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let closure_type = std::any::type_name_of_val(&f);
+
+        Self::with_slot(instance, offset, index, options.priority, || {
+            let mut slot = HookSlot::new(Args::after(f), options)?;
+            slot.closure_type = closure_type;
+
+            Ok(slot)
+        })
+    }
+
+    /// Hook a function with a closure that is also given `&mut` access to `state`, which the hook owns.
+    /// The state can be reached from outside with [`HookFunction::state`].
+    ///
+    /// The state is locked while the closure runs, so calls on other threads wait for each other and
+    /// the closure can be `FnMut`. A
+    /// call that reenters the hook from inside the closure goes to the original function instead, but
+    /// calling the function while holding [`HookFunction::state`] on the same thread deadlocks.
+    ///
+    /// ```rs
+    /// let hook = HookFunction::with_state(
+    ///     instance,
+    ///     0,
+    ///     10,
+    ///     0usize,
+    ///     |ctx, calls: &mut usize, this: &mut Entity, a: usize| -> usize {
+    ///         *calls += 1;
+    ///         call_original(ctx, this, (a,))
+    ///     },
+    /// )?;
+    ///
+    /// println!("{} calls", *hook.state::<usize>().unwrap());
+    /// ```
+    ///
+    pub fn with_state<S: Send + 'static, R: 'static, T: 'static, Args: 'static, F>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        state: S,
+        f: F,
+    ) -> Result<Self>
+    where
+        F: StatefulClosure<S, R, T, Args>,
+    {
+        Self::with_state_options(instance, offset, index, HookOptions::default(), state, f)
+    }
+
+    /// Like [`HookFunction::with_state`], with the [`HookOptions`] in `options`.
+    pub fn with_state_options<S: Send + 'static, R: 'static, T: 'static, Args: 'static, F>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        options: HookOptions,
+        state: S,
+        f: F,
+    ) -> Result<Self>
+    where
+        F: StatefulClosure<S, R, T, Args>,
+    {
+        let state = Arc::new(Mutex::new(state));
+
+        let mut hook = Self::with_slot(instance, offset, index, options.priority, || {
+            let mut slot = HookSlot::new(f.with_state(state.clone()), options)?;
+            slot.closure_type = std::any::type_name::<F>();
+
+            Ok(slot)
+        })?;
+        hook.state = Some(state);
+
+        Ok(hook)
+    }
+
+    /// Lock the state of a hook made with [`HookFunction::with_state`]. Returns `None` if the hook has
+    /// no state, or its state is not an `S`.
+    pub fn state<S: Send + 'static>(&self) -> Option<MutexGuard<'_, S>> {
+        self.state
+            .as_ref()?
+            .downcast_ref::<Mutex<S>>()
+            .map(Mutex::lock)
+    }
+
+    /// Hook function `index` with the slot made by `make_slot`.
+    fn with_slot(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        priority: i32,
+        make_slot: impl FnOnce() -> Result<Box<HookSlot>>,
+    ) -> Result<Self> {
+        // Freed closures can own hooks of this instance, which lock it when they're dropped.
+        free_retired();
+
+        let instance_hook = HookInstance::for_instance(instance as *mut (), offset);
+        let weak = Arc::downgrade(&instance_hook);
+
+        let id = instance_hook
+            .lock()
+            .hook_function_with_slot(index, priority, || {
+                let mut slot = make_slot()?;
+
+                slot.unhook = Some(Box::new(move |id| {
+                    let Some(instance_hook) = weak.upgrade() else {
+                        return;
+                    };
+
+                    let mut instance_hook = instance_hook.lock();
+                    let slot = instance_hook.unhook(index, id, true);
+                    drop(instance_hook);
+
+                    // The slot is still in a call, which retiring it waits for.
+                    if let Some(Some(slot)) = slot {
+                        slot.retire();
+                    }
+                }));
+
+                Ok(slot)
+            })?;
 
         Ok(Self {
-            index,
             instance_hook,
+            index,
+            id,
+            state: None,
         })
     }
+
+    /// Call the closure again after [`HookFunction::disable`].
+    pub fn enable(&self) -> Result<()> {
+        self.set_enabled(true)
+    }
+
+    /// Stop calling the closure, so that calls go to the next hook in the chain or the original
+    /// function. The closure and its trampoline are kept, so the hook can be enabled again without
+    /// being rebuilt.
+    ///
+    /// Calls that are already in the closure carry on.
+    pub fn disable(&self) -> Result<()> {
+        self.set_enabled(false)
+    }
+
+    /// Enable or disable the hook, see [`HookFunction::enable`] and [`HookFunction::disable`].
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        match self.instance_hook.lock().chains.get_mut(&self.index) {
+            Some(chain) => chain.set_enabled(self.id, enabled),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the closure is called, which is the case until [`HookFunction::disable`].
+    pub fn is_enabled(&self) -> bool {
+        self.instance_hook
+            .lock()
+            .chains
+            .get(&self.index)
+            .is_some_and(|chain| chain.is_enabled(self.id))
+    }
+
+    /// Check that the instance still points at the vtable with this hook in it, see
+    /// [`HookInstance::check`].
+    pub fn check_vtable(&self) -> Result<()> {
+        self.instance_hook.lock().check_table()
+    }
+
+    /// Unhook the function without blocking.
+    ///
+    /// Dropping a [`HookFunction`] waits for calls of the closure on other threads to return, so that
+    /// the closure is never called after it's dropped. If any of those calls are still in progress,
+    /// this gives the hook back instead, so that unhooking can be tried again later. Calls that are
+    /// made while trying go to the original function.
+    ///
+    /// Calls that haven't reached the closure yet aren't waited for, see [`HookFunction`].
+    pub fn try_unhook(mut self) -> Result<(), Self> {
+        if self.unhook(false) {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Unhook the function, waiting for calls on other threads if `wait`. Returns whether the function
+    /// was unhooked.
+    fn unhook(&mut self, wait: bool) -> bool {
+        let mut instance_hook = self.instance_hook.lock();
+
+        let Some(slot) = instance_hook.unhook(self.index, self.id, wait) else {
+            return false;
+        };
+
+        // Other calls could be waiting on this instance, so wait after unlocking it.
+        drop(instance_hook);
+
+        if let Some(slot) = slot {
+            slot.close_and_wait();
+            slot.retire();
+        }
+
+        true
+    }
 }
 
 impl Drop for HookFunction {
     fn drop(&mut self) {
-        self.instance_hook
-            .lock()
-            .unhook_function_with_closure(self.index)
-            .unwrap();
+        self.unhook(true);
+    }
+}
+
+/// A hooked function in a class' vtable, which affects every instance using that vtable, including
+/// ones created after the hook.
+///
+/// Unlike [`HookFunction`], the vtable itself is patched, so instances that were given their own copy
+/// of the vtable by a [`HookFunction`] before this hook was made will keep calling the old function.
+/// Instances that are given their copy while the entry is hooked follow the hooks of the entry,
+/// unless the function is hooked on the instance too. The hooks of the instance then call the hooks
+/// of the entry that were in place when the instance's first hook of it was made, which pass calls
+/// straight on once they're unhooked.
+///
+/// Hooks of the same vtable entry are chained like the hooks of a [`HookFunction`].
+#[derive(Debug)]
+pub struct VtableHook {
+    entry: *mut *const (),
+    id: usize,
+}
+
+unsafe impl Send for VtableHook {}
+unsafe impl Sync for VtableHook {}
+
+impl VtableHook {
+    /// The chains of hooked vtable entries, by the address of the entry.
+    fn chains() -> MutexGuard<'static, HashMap<usize, HookChain>> {
+        static CHAINS: OnceLock<Mutex<HashMap<usize, HookChain>>> = OnceLock::new();
+
+        CHAINS.get_or_init(Default::default).lock()
+    }
+
+    /// Hook function `index` of `vtable`, which would usually be found with a pattern scan or from
+    /// RTTI. The closure is the same as for [`HookFunction::new`].
+    ///
+    /// When the [`VtableHook`] is dropped, the original function is put back in the vtable.
+    ///
+    /// # Safety
+    /// * `vtable` must be a valid vtable with more than `index` entries.
+    /// * The function at `index` must take the arguments and return the type of the closure.
+    ///
+    pub unsafe fn new<R: 'static, T: 'static, Args: 'static>(
+        vtable: *const *const (),
+        index: usize,
+        f: impl ThunkableClosure<R, T, Args>,
+    ) -> Result<Self> {
+        Self::with_options(vtable, index, HookOptions::default(), f)
+    }
+
+    /// Like [`VtableHook::new`], but for a function that uses the calling convention `call_conv`, see
+    /// [`HookFunction::with_call_conv`].
+    ///
+    /// # Safety
+    /// * `vtable` must be a valid vtable with more than `index` entries.
+    /// * The function at `index` must take the arguments and return the type of the closure.
+    ///
+    pub unsafe fn with_call_conv<R: 'static, T: 'static, Args: 'static>(
+        vtable: *const *const (),
+        index: usize,
+        call_conv: CallConv,
+        f: impl ThunkableClosure<R, T, Args>,
+    ) -> Result<Self> {
+        let options = HookOptions {
+            call_conv,
+            ..Default::default()
+        };
+
+        Self::with_options(vtable, index, options, f)
+    }
+
+    /// Like [`VtableHook::new`], with the [`HookOptions`] in `options`, see
+    /// [`HookFunction::with_options`].
+    ///
+    /// # Safety
+    /// * `vtable` must be a valid vtable with more than `index` entries.
+    /// * The function at `index` must take the arguments and return the type of the closure.
+    ///
+    pub unsafe fn with_options<R: 'static, T: 'static, Args: 'static>(
+        vtable: *const *const (),
+        index: usize,
+        options: HookOptions,
+        f: impl ThunkableClosure<R, T, Args>,
+    ) -> Result<Self> {
+        free_retired();
+
+        let entry = vtable.add(index) as *mut *const ();
+        let mut slot = HookSlot::new(f, options)?;
+
+        slot.target = Some(HookTarget {
+            instance: std::ptr::null_mut(),
+            index,
+            original_table: vtable,
+            table_len: None,
+        });
+        let key = entry as usize;
+        slot.unhook = Some(Box::new(move |id| {
+            let slot = unhook_from_chain(&mut Self::chains(), key, id, true);
+
+            // The slot is still in a call, which retiring it waits for.
+            if let Some(Some(slot)) = slot {
+                slot.retire();
+            }
+        }));
+
+        let mut chains = Self::chains();
+        let chain = chains
+            .entry(entry as usize)
+            .or_insert_with(|| HookChain::new(entry, options.call_conv));
+
+        let id = chain.insert(options.priority, slot);
+
+        if chain.is_empty() {
+            chains.remove(&(entry as usize));
+        }
+
+        Ok(Self { entry, id: id? })
+    }
+
+    /// Hook function `index` of the vtable that is `offset` bytes from `instance`, for every instance
+    /// that shares it.
+    ///
+    /// # Safety
+    /// * `instance` must be a valid pointer.
+    /// * `*(instance + offset)` must be a valid vtable with more than `index` entries.
+    /// * The function at `index` must take the arguments and return the type of the closure.
+    ///
+    pub unsafe fn for_instance<R: 'static, T: 'static, Args: 'static>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        f: impl ThunkableClosure<R, T, Args>,
+    ) -> Result<Self> {
+        Self::new(
+            HookInstance::get_table(instance as *const (), offset),
+            index,
+            f,
+        )
+    }
+
+    /// Put the hook back in the vtable after [`VtableHook::disable`].
+    pub fn enable(&self) -> Result<()> {
+        self.set_enabled(true)
+    }
+
+    /// Take the hook out of the vtable while keeping its closure and trampoline, see
+    /// [`HookFunction::disable`].
+    pub fn disable(&self) -> Result<()> {
+        self.set_enabled(false)
+    }
+
+    /// Enable or disable the hook, see [`VtableHook::enable`] and [`VtableHook::disable`].
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        match Self::chains().get_mut(&(self.entry as usize)) {
+            Some(chain) => chain.set_enabled(self.id, enabled),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the closure is called, which is the case until [`VtableHook::disable`].
+    pub fn is_enabled(&self) -> bool {
+        Self::chains()
+            .get(&(self.entry as usize))
+            .is_some_and(|chain| chain.is_enabled(self.id))
+    }
+
+    /// Unhook the function without blocking, see [`HookFunction::try_unhook`].
+    pub fn try_unhook(mut self) -> Result<(), Self> {
+        if self.unhook(false) {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Unhook the function, waiting for calls on other threads if `wait`. Returns whether the function
+    /// was unhooked.
+    fn unhook(&mut self, wait: bool) -> bool {
+        let mut chains = Self::chains();
+
+        let Some(slot) = unhook_from_chain(&mut chains, self.entry as usize, self.id, wait) else {
+            return false;
+        };
+
+        drop(chains);
+
+        if let Some(slot) = slot {
+            slot.close_and_wait();
+            slot.retire();
+        }
+
+        true
+    }
+}
+
+impl Drop for VtableHook {
+    fn drop(&mut self) {
+        self.unhook(true);
+    }
+}
+
+/// Close hook `id` in the chain at `key` of `chains` and remove it, removing the chain too if it was
+/// the last hook. Returns `None` if there are calls of the hook on other threads and not `wait`, or
+/// the slot to wait for and retire otherwise, which is `None` if the hook was already removed.
+fn unhook_from_chain(
+    chains: &mut HashMap<usize, HookChain>,
+    key: usize,
+    id: usize,
+    wait: bool,
+) -> Option<Option<Box<HookSlot>>> {
+    let Some(chain) = chains.get_mut(&key) else {
+        return Some(None);
+    };
+
+    let Some(slot) = chain.slot(id) else {
+        return Some(None);
+    };
+
+    if !slot.close() && !wait {
+        slot.reopen();
+        return None;
+    }
+
+    let slot = chain.remove(id).unwrap();
+
+    if chain.is_empty() {
+        chains.remove(&key);
+    }
+
+    Some(slot)
+}
+
+/// Write `value` to `address`, which is usually in a read only section, making it writable while
+/// doing so.
+unsafe fn write_protected(address: *mut *const (), value: *const ()) -> Result<()> {
+    let _guard = region::protect_with_handle(
+        address,
+        std::mem::size_of::<*const ()>(),
+        region::Protection::READ_WRITE,
+    )?;
+
+    address.write_volatile(value);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+
+    #[repr(C)]
+    struct Obj {
+        vtable: *const *const (),
+        value: usize,
+    }
+
+    unsafe extern "C" fn get(this: *mut Obj, a: usize) -> usize {
+        (*this).value + a
+    }
+
+    static TABLE: [unsafe extern "C" fn(*mut Obj, usize) -> usize; 1] = [get];
+
+    #[test]
+    fn try_unhook_fails_while_a_call_is_in_flight() {
+        set_vtable_len(TABLE.as_ptr() as *const *const (), TABLE.len());
+        let obj = Box::into_raw(Box::new(Obj {
+            vtable: TABLE.as_ptr() as *const *const (),
+            value: 1,
+        }));
+
+        let entered = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        let hook = {
+            let (entered, release) = (entered.clone(), release.clone());
+
+            HookFunction::new(obj, 0, 0, move |ctx, this: &mut Obj, a: usize| -> usize {
+                entered.wait();
+                release.wait();
+                call_original(ctx, this, (a,)) + 10
+            })
+            .unwrap()
+        };
+
+        let address = obj as usize;
+        let caller = std::thread::spawn(move || unsafe {
+            call::call_virtual::<usize, (usize,)>(address as *mut Obj, 0, (0,))
+        });
+
+        entered.wait();
+        let Err(hook) = hook.try_unhook() else {
+            panic!("unhooked while a call was in flight");
+        };
+
+        release.wait();
+        assert_eq!(caller.join().unwrap(), 11);
+        assert!(hook.try_unhook().is_ok());
+
+        drop(unsafe { Box::from_raw(obj) });
     }
 }
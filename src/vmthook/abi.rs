@@ -0,0 +1,868 @@
+//! Lowering of Rust values to Cranelift signatures.
+//!
+//! Scalars map onto a single Cranelift parameter, but `#[repr(C)]` aggregates passed by value are
+//! split into registers, copied onto the stack or passed by reference depending on the calling
+//! convention. The trampoline and the Rust thunk don't always agree on how a value is passed (the
+//! thunk takes the slot of the hook first, and is a free function rather than a member function),
+//! so [`forward_call`] converts between the two by going through memory where needed.
+
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use cranelift::codegen::ir::{ArgumentPurpose, SigRef};
+use cranelift::prelude::*;
+
+use super::thunk::AsCraneliftAbi;
+
+/// How a Rust type is passed to and returned from functions.
+#[derive(Clone, Debug)]
+pub enum CraneliftValue {
+    /// `()`, which is only valid as a return type.
+    Void,
+    /// A single Cranelift basic type.
+    Scalar(AbiParam),
+    /// A `#[repr(C)]` aggregate that is passed by value.
+    Aggregate(AggregateLayout),
+}
+
+/// The layout of a `#[repr(C)]` aggregate, flattened down to its scalar fields.
+#[derive(Clone, Debug)]
+pub struct AggregateLayout {
+    size: u32,
+    align: u32,
+    fields: Vec<(u32, Type)>,
+}
+
+impl AggregateLayout {
+    /// An empty layout with the size and alignment of `T`. Add its fields with [`Self::field`].
+    pub fn of<T>() -> Self {
+        Self {
+            size: std::mem::size_of::<T>() as u32,
+            align: std::mem::align_of::<T>() as u32,
+            fields: vec![],
+        }
+    }
+
+    /// Add a field of type `F` at `offset` bytes. Nested aggregates are flattened.
+    pub fn field<F: AsCraneliftValue>(mut self, offset: usize) -> Self {
+        let offset = offset as u32;
+
+        match F::as_cranelift_value() {
+            CraneliftValue::Void => {}
+            CraneliftValue::Scalar(param) => self.fields.push((offset, param.value_type)),
+            CraneliftValue::Aggregate(layout) => self.fields.extend(
+                layout
+                    .fields
+                    .into_iter()
+                    .map(|(field_offset, ty)| (offset + field_offset, ty)),
+            ),
+        }
+
+        self
+    }
+}
+
+/// [`AsCraneliftValue`] is how a type is passed to and returned from a function.
+///
+/// It is implemented for every [`AsCraneliftAbi`] type and `()`. Implement it for `#[repr(C)]`
+/// structs that are passed by value with [`crate::cranelift_aggregate`].
+pub trait AsCraneliftValue {
+    fn as_cranelift_value() -> CraneliftValue;
+}
+
+impl<T: AsCraneliftAbi> AsCraneliftValue for T {
+    fn as_cranelift_value() -> CraneliftValue {
+        CraneliftValue::Scalar(T::as_cranelift_abi())
+    }
+}
+
+impl AsCraneliftValue for () {
+    fn as_cranelift_value() -> CraneliftValue {
+        CraneliftValue::Void
+    }
+}
+
+/// Implement [`AsCraneliftValue`] for a `#[repr(C)]` struct, so that it can be passed to and
+/// returned from hooked functions by value.
+///
+/// Every field has to be listed with its type, and the types must implement [`AsCraneliftValue`].
+///
+/// ```rs
+/// #[repr(C)]
+/// struct Vec3 {
+///     x: f32,
+///     y: f32,
+///     z: f32,
+/// }
+///
+/// cranelift_aggregate!(Vec3 { x: f32, y: f32, z: f32 });
+/// ```
+///
+#[macro_export]
+macro_rules! cranelift_aggregate {
+    ($t:ty { $($field:ident: $field_ty:ty),* $(,)? }) => {
+        impl $crate::AsCraneliftValue for $t {
+            fn as_cranelift_value() -> $crate::CraneliftValue {
+                $crate::CraneliftValue::Aggregate(
+                    $crate::AggregateLayout::of::<$t>()
+                        $(.field::<$field_ty>(::core::mem::offset_of!($t, $field)))*
+                )
+            }
+        }
+    };
+}
+
+/// The calling convention of a hooked function.
+///
+/// The rust side of a hook always uses `extern "C"`, and the trampoline and calls to the original
+/// function convert between that and the convention of the hooked function.
+///
+/// There are no 32-bit conventions like `thiscall` and `stdcall`, or `vectorcall`, as Cranelift
+/// can't generate code for them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum CallConv {
+    /// The default convention of the platform, the same as `extern "C"`.
+    #[default]
+    Default,
+    /// The System V AMD64 convention, used on Linux and macOS.
+    #[cfg(target_arch = "x86_64")]
+    SystemV,
+    /// The Microsoft x64 convention, used on Windows and by Windows code running under Wine.
+    #[cfg(target_arch = "x86_64")]
+    Win64,
+}
+
+impl CallConv {
+    pub(crate) fn isa_call_conv(self, default: isa::CallConv) -> isa::CallConv {
+        match self {
+            CallConv::Default => default,
+            #[cfg(target_arch = "x86_64")]
+            CallConv::SystemV => isa::CallConv::SystemV,
+            #[cfg(target_arch = "x86_64")]
+            CallConv::Win64 => isa::CallConv::WindowsFastcall,
+        }
+    }
+}
+
+/// Whether `R` is returned through a hidden pointer when it is returned from a C++ member function,
+/// but not when it is returned from a Rust `extern "C"` function.
+///
+/// This is the case for every aggregate on Win64, where member functions always return them
+/// indirectly, and take the pointer after `this` rather than as the first argument.
+pub(crate) fn member_returns_indirectly<R: AsCraneliftValue>() -> bool {
+    cfg!(all(target_os = "windows", target_arch = "x86_64"))
+        && matches!(R::as_cranelift_value(), CraneliftValue::Aggregate(_))
+}
+
+/// What kind of function a signature is for, as C++ member functions return aggregates differently
+/// on some platforms.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum FunctionKind {
+    Member,
+    Free,
+}
+
+/// A value that has been lowered to Cranelift params or returns.
+#[derive(Debug)]
+struct Lowered {
+    /// The Cranelift params (or returns) that make up the value.
+    range: Range<usize>,
+    /// The offset of each Cranelift value into the Rust value, or `None` if the only Cranelift value
+    /// is a pointer to it.
+    offsets: Option<Vec<u32>>,
+    size: u32,
+    align: u32,
+}
+
+#[derive(Debug)]
+enum LoweredReturn {
+    /// Returned in the `range` of the signature returns.
+    Direct(Lowered),
+    /// Written through the pointer in the hidden param at `param`.
+    Indirect { param: usize, size: u32, align: u32 },
+}
+
+/// A signature along with where each value ended up in it.
+#[derive(Debug)]
+pub(crate) struct LoweredSignature {
+    pub(crate) signature: Signature,
+    params: Vec<Lowered>,
+    returns: LoweredReturn,
+}
+
+/// How an aggregate is passed.
+enum Class {
+    /// In registers, as these Cranelift values at these offsets.
+    Registers(Vec<(u32, Type)>),
+    /// Copied onto the stack (SysV `MEMORY` class).
+    Stack,
+    /// As a pointer to a copy.
+    Reference,
+}
+
+/// Number of argument registers left, for conventions where aggregates that don't fit in the
+/// remaining registers are passed differently.
+struct Registers {
+    int: usize,
+    float: usize,
+}
+
+impl Registers {
+    fn new(call_conv: isa::CallConv) -> Self {
+        if cfg!(target_arch = "aarch64") {
+            Self { int: 8, float: 8 }
+        } else if call_conv == isa::CallConv::WindowsFastcall {
+            // Win64 assigns registers by position, and never splits aggregates.
+            Self {
+                int: usize::MAX,
+                float: usize::MAX,
+            }
+        } else {
+            Self { int: 6, float: 8 }
+        }
+    }
+
+    /// Take registers for `types`, or return false without taking any if there aren't enough.
+    fn take(&mut self, types: impl Iterator<Item = Type> + Clone) -> bool {
+        let int = types.clone().filter(|ty| !ty.is_float()).count();
+        let float = types.filter(|ty| ty.is_float()).count();
+
+        if int > self.int || float > self.float {
+            return false;
+        }
+
+        self.int -= int;
+        self.float -= float;
+        true
+    }
+
+    /// Take the registers for a scalar, which is passed on the stack if there aren't enough.
+    fn take_scalar(&mut self, ty: Type) {
+        if ty.is_float() {
+            self.float = self.float.saturating_sub(1);
+        } else {
+            // i128 takes two registers, or moves past them even if it ends up on the stack.
+            let registers = if ty == types::I128 { 2 } else { 1 };
+            self.int = self.int.saturating_sub(registers);
+        }
+    }
+}
+
+/// The smallest integer type that can hold `bytes` bytes.
+fn int_type(bytes: u32) -> Type {
+    match bytes {
+        0..=1 => types::I8,
+        2 => types::I16,
+        3..=4 => types::I32,
+        _ => types::I64,
+    }
+}
+
+fn classify(layout: &AggregateLayout, call_conv: isa::CallConv) -> Class {
+    let AggregateLayout {
+        size,
+        align: _,
+        fields,
+    } = layout;
+
+    let unaligned = fields.iter().any(|(offset, ty)| offset % ty.bytes() != 0);
+
+    if cfg!(target_arch = "aarch64") {
+        // AAPCS64: homogeneous float aggregates go in float registers, other aggregates of up to 16
+        // bytes go in general registers, and anything bigger is passed by reference.
+        let homogeneous = (1..=4).contains(&fields.len())
+            && fields[0].1.is_float()
+            && fields
+                .iter()
+                .enumerate()
+                .all(|(i, (offset, ty))| *ty == fields[0].1 && *offset == i as u32 * ty.bytes());
+
+        if homogeneous {
+            return Class::Registers(fields.clone());
+        }
+
+        if *size > 16 {
+            return Class::Reference;
+        }
+
+        return Class::Registers(
+            (0..size.div_ceil(8))
+                .map(|chunk| (chunk * 8, int_type(size - chunk * 8)))
+                .collect(),
+        );
+    }
+
+    if call_conv == isa::CallConv::WindowsFastcall {
+        // Win64: aggregates that are exactly the size of an integer register are passed as one,
+        // everything else is passed by reference.
+        return match size {
+            1 | 2 | 4 | 8 => Class::Registers(vec![(0, int_type(*size))]),
+            _ => Class::Reference,
+        };
+    }
+
+    // SysV: aggregates of up to 16 bytes are split into eightbytes, which go in float registers if
+    // they only contain floats and in general registers otherwise.
+    if *size > 16 || unaligned {
+        return Class::Stack;
+    }
+
+    Class::Registers(
+        (0..size.div_ceil(8))
+            .map(|chunk| {
+                let start = chunk * 8;
+                let bytes = (size - start).min(8);
+
+                let mut chunk_fields = fields
+                    .iter()
+                    .filter(|(offset, _)| (start..start + 8).contains(offset))
+                    .peekable();
+
+                let only_floats =
+                    chunk_fields.peek().is_some() && chunk_fields.all(|(_, ty)| ty.is_float());
+
+                let ty = match (only_floats, bytes) {
+                    (true, 0..=4) => types::F32,
+                    (true, _) => types::F64,
+                    (false, bytes) => int_type(bytes),
+                };
+
+                (start, ty)
+            })
+            .collect(),
+    )
+}
+
+/// Lower a function signature with the calling convention `call_conv`.
+///
+/// `params` are the values that the function takes, including `this` for member functions.
+pub(crate) fn lower_signature(
+    call_conv: isa::CallConv,
+    kind: FunctionKind,
+    returns: &CraneliftValue,
+    params: &[CraneliftValue],
+) -> Result<LoweredSignature> {
+    let mut signature = Signature::new(call_conv);
+    let mut registers = Registers::new(call_conv);
+
+    let indirect_return = match returns {
+        CraneliftValue::Aggregate(layout) => {
+            let indirect = (call_conv == isa::CallConv::WindowsFastcall
+                && kind == FunctionKind::Member)
+                || !matches!(classify(layout, call_conv), Class::Registers(_));
+
+            indirect.then_some((layout.size, layout.align))
+        }
+        _ => None,
+    };
+
+    // The hidden return pointer is the first argument, apart from for Win64 member functions where
+    // it comes after `this`.
+    let return_pointer_after_this =
+        call_conv == isa::CallConv::WindowsFastcall && kind == FunctionKind::Member;
+
+    let mut lowered_returns = None;
+
+    let mut push_return_pointer = |signature: &mut Signature, registers: &mut Registers| {
+        if let Some((size, align)) = indirect_return {
+            let param = signature.params.len();
+            signature
+                .params
+                .push(AbiParam::special(types::I64, ArgumentPurpose::StructReturn));
+
+            // x8 is used for the return pointer on aarch64, which isn't an argument register.
+            if cfg!(not(target_arch = "aarch64")) {
+                registers.take_scalar(types::I64);
+            }
+
+            lowered_returns = Some(LoweredReturn::Indirect { param, size, align });
+        }
+    };
+
+    if !return_pointer_after_this {
+        push_return_pointer(&mut signature, &mut registers);
+    }
+
+    let mut lowered_params = vec![];
+
+    for (i, param) in params.iter().enumerate() {
+        let start = signature.params.len();
+
+        let (offsets, size, align) = match param {
+            CraneliftValue::Void => bail!("`()` can only be used as a return type"),
+            CraneliftValue::Scalar(param) => {
+                registers.take_scalar(param.value_type);
+                signature.params.push(*param);
+
+                (
+                    Some(vec![0]),
+                    param.value_type.bytes(),
+                    param.value_type.bytes(),
+                )
+            }
+            CraneliftValue::Aggregate(layout) => {
+                let class = match classify(layout, call_conv) {
+                    Class::Registers(pieces)
+                        if !registers.take(pieces.iter().map(|(_, ty)| *ty)) =>
+                    {
+                        if cfg!(target_arch = "aarch64") {
+                            bail!("aggregates that are passed on the stack are not supported on aarch64");
+                        }
+
+                        Class::Stack
+                    }
+                    class => class,
+                };
+
+                let offsets = match class {
+                    Class::Registers(pieces) => {
+                        signature
+                            .params
+                            .extend(pieces.iter().map(|(_, ty)| AbiParam::new(*ty)));
+
+                        Some(pieces.into_iter().map(|(offset, _)| offset).collect())
+                    }
+                    Class::Stack => {
+                        signature.params.push(AbiParam::special(
+                            types::I64,
+                            ArgumentPurpose::StructArgument(layout.size.next_multiple_of(8)),
+                        ));
+
+                        None
+                    }
+                    Class::Reference => {
+                        registers.take_scalar(types::I64);
+                        signature.params.push(AbiParam::new(types::I64));
+
+                        None
+                    }
+                };
+
+                (offsets, layout.size, layout.align)
+            }
+        };
+
+        lowered_params.push(Lowered {
+            range: start..signature.params.len(),
+            offsets,
+            size,
+            align,
+        });
+
+        if i == 0 && return_pointer_after_this {
+            push_return_pointer(&mut signature, &mut registers);
+        }
+    }
+
+    let returns = match lowered_returns {
+        Some(returns) => returns,
+        None => {
+            let (offsets, size, align) = match returns {
+                CraneliftValue::Void => (vec![], 0, 1),
+                CraneliftValue::Scalar(param) => {
+                    signature.returns.push(*param);
+                    (vec![0], param.value_type.bytes(), param.value_type.bytes())
+                }
+                CraneliftValue::Aggregate(layout) => {
+                    let Class::Registers(pieces) = classify(layout, call_conv) else {
+                        unreachable!("aggregates not returned in registers are returned indirectly")
+                    };
+
+                    signature
+                        .returns
+                        .extend(pieces.iter().map(|(_, ty)| AbiParam::new(*ty)));
+
+                    (
+                        pieces.into_iter().map(|(offset, _)| offset).collect(),
+                        layout.size,
+                        layout.align,
+                    )
+                }
+            };
+
+            LoweredReturn::Direct(Lowered {
+                range: 0..signature.returns.len(),
+                offsets: Some(offsets),
+                size,
+                align,
+            })
+        }
+    };
+
+    Ok(LoweredSignature {
+        signature,
+        params: lowered_params,
+        returns,
+    })
+}
+
+/// Make a stack slot that can hold a value of `size` and `align`, and return its address.
+fn stack_value(builder: &mut FunctionBuilder, size: u32, align: u32) -> Value {
+    let slot = builder.create_sized_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        // Pieces are register sized, so the last one can go past the end of the value.
+        size.next_multiple_of(8),
+        align.max(1).trailing_zeros() as u8,
+    ));
+
+    builder.ins().stack_addr(types::I64, slot, 0)
+}
+
+/// Get a pointer to a lowered value, storing it to the stack if it is in registers.
+fn value_address(builder: &mut FunctionBuilder, lowered: &Lowered, values: &[Value]) -> Value {
+    let Some(offsets) = &lowered.offsets else {
+        return values[0];
+    };
+
+    let address = stack_value(builder, lowered.size, lowered.align);
+
+    for (value, offset) in values.iter().zip(offsets) {
+        builder
+            .ins()
+            .store(MemFlags::trusted(), *value, address, *offset as i32);
+    }
+
+    address
+}
+
+/// Load the pieces of a lowered value from `address`.
+fn load_value(
+    builder: &mut FunctionBuilder,
+    lowered: &Lowered,
+    types: &[AbiParam],
+    address: Value,
+) -> Vec<Value> {
+    let Some(offsets) = &lowered.offsets else {
+        return vec![address];
+    };
+
+    offsets
+        .iter()
+        .zip(types)
+        .map(|(offset, param)| {
+            builder.ins().load(
+                param.value_type,
+                MemFlags::trusted(),
+                address,
+                *offset as i32,
+            )
+        })
+        .collect()
+}
+
+/// Convert `values`, lowered as `from`, into how they are lowered in `to`.
+fn convert_value(
+    builder: &mut FunctionBuilder,
+    (from, from_types): (&Lowered, &[AbiParam]),
+    (to, to_types): (&Lowered, &[AbiParam]),
+    values: &[Value],
+) -> Vec<Value> {
+    let same_registers = from.offsets.is_some()
+        && from.offsets == to.offsets
+        && from_types
+            .iter()
+            .map(|param| param.value_type)
+            .eq(to_types.iter().map(|param| param.value_type));
+
+    if same_registers {
+        return values.to_vec();
+    }
+
+    let address = value_address(builder, from, values);
+    load_value(builder, to, to_types, address)
+}
+
+/// Forward the params of the function being built, which has the signature `from`, on to a call of
+/// `callee` with the signature `to`, and return its result.
+///
+/// `to` can take extra leading scalar params, which are given by `leading`.
+pub(crate) fn forward_call(
+    builder: &mut FunctionBuilder,
+    (from, from_params): (&LoweredSignature, &[Value]),
+    (to, to_ref, callee): (&LoweredSignature, SigRef, Value),
+    leading: &[Value],
+) {
+    let mut args = vec![None; to.signature.params.len()];
+
+    for (lowered, value) in to.params.iter().zip(leading) {
+        args[lowered.range.start] = Some(*value);
+    }
+
+    for (from_lowered, to_lowered) in from.params.iter().zip(&to.params[leading.len()..]) {
+        let values = convert_value(
+            builder,
+            (
+                from_lowered,
+                &from.signature.params[from_lowered.range.clone()],
+            ),
+            (to_lowered, &to.signature.params[to_lowered.range.clone()]),
+            &from_params[from_lowered.range.clone()],
+        );
+
+        for (i, value) in to_lowered.range.clone().zip(values) {
+            args[i] = Some(value);
+        }
+    }
+
+    // Where the callee is going to write its result to, if it returns indirectly.
+    let return_address = match (&from.returns, &to.returns) {
+        (_, LoweredReturn::Direct(_)) => None,
+        (LoweredReturn::Indirect { param, .. }, LoweredReturn::Indirect { .. }) => {
+            Some(from_params[*param])
+        }
+        (LoweredReturn::Direct(_), LoweredReturn::Indirect { size, align, .. }) => {
+            Some(stack_value(builder, *size, *align))
+        }
+    };
+
+    if let LoweredReturn::Indirect { param, .. } = &to.returns {
+        args[*param] = return_address;
+    }
+
+    let args = args
+        .into_iter()
+        .map(|arg| arg.expect("every param of the callee is forwarded"))
+        .collect::<Vec<_>>();
+
+    let call = builder.ins().call_indirect(to_ref, callee, &args);
+    let results = builder.inst_results(call).to_vec();
+
+    let returns = match (&from.returns, &to.returns) {
+        (LoweredReturn::Direct(from_lowered), LoweredReturn::Direct(to_lowered)) => convert_value(
+            builder,
+            (to_lowered, &to.signature.returns),
+            (from_lowered, &from.signature.returns),
+            &results,
+        ),
+        (LoweredReturn::Direct(from_lowered), LoweredReturn::Indirect { .. }) => load_value(
+            builder,
+            from_lowered,
+            &from.signature.returns,
+            return_address.unwrap(),
+        ),
+        (LoweredReturn::Indirect { param, .. }, LoweredReturn::Direct(to_lowered)) => {
+            let address = from_params[*param];
+
+            for (value, offset) in results.iter().zip(to_lowered.offsets.iter().flatten()) {
+                builder
+                    .ins()
+                    .store(MemFlags::trusted(), *value, address, *offset as i32);
+            }
+
+            // The return pointer is returned implicitly.
+            vec![]
+        }
+        (LoweredReturn::Indirect { .. }, LoweredReturn::Indirect { .. }) => vec![],
+    };
+
+    builder.ins().return_(&returns);
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    fn layout(size: u32, align: u32, fields: &[(u32, Type)]) -> AggregateLayout {
+        AggregateLayout {
+            size,
+            align,
+            fields: fields.to_vec(),
+        }
+    }
+
+    fn vec3() -> AggregateLayout {
+        layout(12, 4, &[(0, types::F32), (4, types::F32), (8, types::F32)])
+    }
+
+    fn registers(class: Class) -> Option<Vec<(u32, Type)>> {
+        match class {
+            Class::Registers(pieces) => Some(pieces),
+            _ => None,
+        }
+    }
+
+    fn scalar(ty: Type) -> CraneliftValue {
+        CraneliftValue::Scalar(AbiParam::new(ty))
+    }
+
+    #[test]
+    fn system_v_packs_floats_into_eightbytes() {
+        let pieces = registers(classify(&vec3(), isa::CallConv::SystemV));
+
+        assert_eq!(pieces, Some(vec![(0, types::F64), (8, types::F32)]));
+    }
+
+    #[test]
+    fn system_v_mixed_eightbyte_is_integer() {
+        let mixed = layout(12, 4, &[(0, types::F32), (4, types::I32), (8, types::F32)]);
+        let pieces = registers(classify(&mixed, isa::CallConv::SystemV));
+
+        assert_eq!(pieces, Some(vec![(0, types::I64), (8, types::F32)]));
+    }
+
+    #[test]
+    fn big_aggregates_are_not_in_registers() {
+        let big = layout(24, 8, &[(0, types::I64), (8, types::I64), (16, types::I64)]);
+
+        assert!(matches!(
+            classify(&big, isa::CallConv::SystemV),
+            Class::Stack
+        ));
+        assert!(matches!(
+            classify(&big, isa::CallConv::WindowsFastcall),
+            Class::Reference
+        ));
+    }
+
+    #[test]
+    fn win64_only_passes_register_sized_aggregates_in_registers() {
+        let pair = layout(8, 4, &[(0, types::F32), (4, types::F32)]);
+
+        assert_eq!(
+            registers(classify(&pair, isa::CallConv::WindowsFastcall)),
+            Some(vec![(0, types::I64)])
+        );
+        assert!(matches!(
+            classify(&vec3(), isa::CallConv::WindowsFastcall),
+            Class::Reference
+        ));
+    }
+
+    #[test]
+    fn win64_member_return_pointer_comes_after_this() {
+        let returns = CraneliftValue::Aggregate(layout(8, 8, &[(0, types::I64)]));
+        let params = [scalar(types::I64), scalar(types::I32)];
+
+        let member = lower_signature(
+            isa::CallConv::WindowsFastcall,
+            FunctionKind::Member,
+            &returns,
+            &params,
+        )
+        .unwrap();
+
+        let purposes = member
+            .signature
+            .params
+            .iter()
+            .map(|param| param.purpose)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            purposes,
+            [
+                ArgumentPurpose::Normal,
+                ArgumentPurpose::StructReturn,
+                ArgumentPurpose::Normal
+            ]
+        );
+        assert!(matches!(
+            member.returns,
+            LoweredReturn::Indirect { param: 1, .. }
+        ));
+        assert_eq!(member.params[1].range, 2..3);
+
+        // The same aggregate fits in a register when it's returned from a free function.
+        let free = lower_signature(
+            isa::CallConv::WindowsFastcall,
+            FunctionKind::Free,
+            &returns,
+            &params,
+        )
+        .unwrap();
+
+        assert_eq!(free.signature.params.len(), 2);
+        assert_eq!(free.signature.returns.len(), 1);
+    }
+
+    #[test]
+    fn system_v_return_pointer_is_first() {
+        let returns = CraneliftValue::Aggregate(layout(24, 8, &[(0, types::I64); 3]));
+        let lowered = lower_signature(
+            isa::CallConv::SystemV,
+            FunctionKind::Member,
+            &returns,
+            &[scalar(types::I64)],
+        )
+        .unwrap();
+
+        assert_eq!(
+            lowered.signature.params[0].purpose,
+            ArgumentPurpose::StructReturn
+        );
+        assert!(matches!(
+            lowered.returns,
+            LoweredReturn::Indirect { param: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn system_v_aggregates_go_on_the_stack_when_registers_run_out() {
+        let pair = CraneliftValue::Aggregate(layout(16, 8, &[(0, types::I64), (8, types::I64)]));
+
+        // One register is left, and aggregates are never split between registers and the stack.
+        let mut params = vec![scalar(types::I64); 5];
+        params.push(pair.clone());
+        params.push(scalar(types::I64));
+
+        let lowered = lower_signature(
+            isa::CallConv::SystemV,
+            FunctionKind::Free,
+            &CraneliftValue::Void,
+            &params,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lowered.signature.params[5].purpose,
+            ArgumentPurpose::StructArgument(16)
+        );
+        assert!(lowered.params[5].offsets.is_none());
+        assert_eq!(lowered.params[6].range, 6..7);
+
+        // With enough registers left it's split into two.
+        let lowered = lower_signature(
+            isa::CallConv::SystemV,
+            FunctionKind::Free,
+            &CraneliftValue::Void,
+            &[scalar(types::I64), pair],
+        )
+        .unwrap();
+
+        assert_eq!(lowered.params[1].range, 1..3);
+        assert_eq!(lowered.params[1].offsets, Some(vec![0, 8]));
+    }
+
+    #[test]
+    fn system_v_float_registers_run_out_separately() {
+        let mut params = vec![scalar(types::F64); 8];
+        params.push(CraneliftValue::Aggregate(vec3()));
+
+        let lowered = lower_signature(
+            isa::CallConv::SystemV,
+            FunctionKind::Free,
+            &CraneliftValue::Void,
+            &params,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lowered.signature.params[8].purpose,
+            ArgumentPurpose::StructArgument(16)
+        );
+
+        // Taking up every integer register doesn't affect an aggregate of floats.
+        let mut params = vec![scalar(types::I64); 6];
+        params.push(CraneliftValue::Aggregate(vec3()));
+
+        let lowered = lower_signature(
+            isa::CallConv::SystemV,
+            FunctionKind::Free,
+            &CraneliftValue::Void,
+            &params,
+        )
+        .unwrap();
+
+        assert_eq!(lowered.params[6].offsets, Some(vec![0, 8]));
+    }
+}
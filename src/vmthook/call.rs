@@ -0,0 +1,116 @@
+use super::thunk::VirtualArgs;
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+use anyhow::{bail, Result};
+
+/// Gets the function in slot `index` of the vtable that is `offset` bytes from `instance`.
+///
+/// # Safety
+/// * `*(instance + offset)` must be a valid vtable with more than `index` entries.
+///
+unsafe fn virtual_slot(instance: *mut (), offset: usize, index: usize) -> *const () {
+    let table = *((instance as *const u8).add(offset) as *const *const *const ());
+    *table.add(index)
+}
+
+/// A function taken from a vtable, which can be called like a virtual function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OriginalFunction(*const ());
+
+impl OriginalFunction {
+    pub(super) fn new(function: *const ()) -> Self {
+        Self(function)
+    }
+
+    pub fn address(self) -> *const () {
+        self.0
+    }
+
+    /// Call the function with `this` and `args`, like [`call_virtual`].
+    ///
+    /// # Safety
+    /// * `this` must be a valid pointer to an instance the function can be called on.
+    /// * The function must take `Args` and return `R`, with the platform's default calling
+    ///   convention.
+    ///
+    pub unsafe fn call<R: 'static, Args: VirtualArgs<R>>(
+        self,
+        this: *mut impl Sized,
+        args: Args,
+    ) -> R {
+        args.call_function(self.0, this as *mut ())
+    }
+}
+
+/// Call virtual function `index` of `instance`, assuming the vtable is at 0 bytes from `instance`.
+///
+/// The return type and arguments of the function are given by `R` and `Args`:
+///
+/// ```rs
+/// let health = unsafe { call_virtual::<i32, (usize,)>(entity, 12, (0,)) };
+/// ```
+///
+/// # Safety
+/// * `instance` must be a valid pointer.
+/// * `*(instance)` must be a valid vtable with more than `index` entries.
+/// * The function at `index` must take `Args` and return `R`.
+///
+pub unsafe fn call_virtual<R: 'static, Args: VirtualArgs<R>>(
+    instance: *mut impl Sized,
+    index: usize,
+    args: Args,
+) -> R {
+    call_virtual_at(instance, 0, index, args)
+}
+
+/// Call virtual function `index` of the vtable that is `offset` bytes from `instance`.
+///
+/// This is used for the vtables of secondary bases, and the function is called with
+/// `instance + offset` as `this`.
+///
+/// # Safety
+/// * `instance` must be a valid pointer.
+/// * `*(instance + offset)` must be a valid vtable with more than `index` entries.
+/// * The function at `index` must take `Args` and return `R`.
+///
+pub unsafe fn call_virtual_at<R: 'static, Args: VirtualArgs<R>>(
+    instance: *mut impl Sized,
+    offset: usize,
+    index: usize,
+    args: Args,
+) -> R {
+    let this = (instance as *mut u8).add(offset) as *mut ();
+    let function = virtual_slot(instance as *mut (), offset, index);
+
+    args.call_function(function, this)
+}
+
+/// Like [`call_virtual_at`], but first checks that the function at `index` is inside the code section
+/// of `module`, and fails instead of calling it if not.
+///
+/// # Safety
+/// * `instance` must be a valid pointer.
+/// * `*(instance + offset)` must be a valid vtable with more than `index` entries.
+/// * The function at `index` must take `Args` and return `R`.
+///
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+pub unsafe fn call_virtual_checked<R: 'static, Args: VirtualArgs<R>>(
+    module: &crate::Module,
+    instance: *mut impl Sized,
+    offset: usize,
+    index: usize,
+    args: Args,
+) -> Result<R> {
+    let function = virtual_slot(instance as *mut (), offset, index);
+
+    if !module
+        .code_section_address_range()
+        .contains(&(function as usize))
+    {
+        bail!(
+            "virtual function {index} ({function:?}) is outside of the code section of its module"
+        );
+    }
+
+    Ok(call_virtual_at(instance, offset, index, args))
+}
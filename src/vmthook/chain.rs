@@ -0,0 +1,360 @@
+use std::sync::{Arc, Weak};
+
+use anyhow::{bail, Result};
+
+use super::{abi::CallConv, write_protected, HookSlot};
+
+/// A hook in a [`HookChain`].
+#[derive(Debug)]
+struct ChainLink {
+    id: usize,
+    priority: i32,
+    enabled: bool,
+    slot: Box<HookSlot>,
+}
+
+/// The hooks of one table entry.
+///
+/// The entry points to the trampoline of the first enabled hook, the original function of each hook
+/// is the next enabled hook in the chain, and the original function of the last hook is the function
+/// that was in the entry before it was hooked.
+#[derive(Debug)]
+pub(super) struct HookChain {
+    entry: *mut *const (),
+    original: *const (),
+    call_conv: CallConv,
+    // Ordered by the order they are called in.
+    links: Vec<ChainLink>,
+    // Entries of shadow tables that were copied from the entry while it was hooked, which are kept
+    // pointing where the entry does, unless they were hooked themselves.
+    copies: Vec<*mut *const ()>,
+    // Alive while any of the copies is, and given to the slots that are removed so that they aren't
+    // freed while calls can still reach them through a copy.
+    copied: Weak<()>,
+}
+
+unsafe impl Send for HookChain {}
+unsafe impl Sync for HookChain {}
+
+impl HookChain {
+    /// Make a chain for the function in `entry`, which uses `call_conv`.
+    ///
+    /// # Safety
+    /// * `entry` must be valid for reads and writes for as long as the chain is alive.
+    ///
+    pub(super) unsafe fn new(entry: *mut *const (), call_conv: CallConv) -> Self {
+        Self {
+            entry,
+            original: *entry,
+            call_conv,
+            links: vec![],
+            copies: vec![],
+            copied: Weak::new(),
+        }
+    }
+
+    /// Add `slot` to the chain, before the hooks with a lower priority and hooks with the same priority
+    /// that were added before it. Returns an id for [`HookChain::slot`] and [`HookChain::remove`].
+    pub(super) fn insert(&mut self, priority: i32, slot: Box<HookSlot>) -> Result<usize> {
+        if slot.call_conv != self.call_conv {
+            bail!(
+                "hooks of the same function must use the same calling convention, this one uses \
+                 {:?} and others use {:?}",
+                slot.call_conv,
+                self.call_conv
+            );
+        }
+
+        let id = slot.id;
+        let position = self
+            .links
+            .iter()
+            .position(|link| link.priority <= priority)
+            .unwrap_or(self.links.len());
+
+        self.links.insert(
+            position,
+            ChainLink {
+                id,
+                priority,
+                enabled: true,
+                slot,
+            },
+        );
+
+        if let Err(error) = self.relink() {
+            self.links.remove(position);
+            return Err(error);
+        }
+
+        Ok(id)
+    }
+
+    /// The slot of hook `id`.
+    pub(super) fn slot(&self, id: usize) -> Option<&HookSlot> {
+        self.links
+            .iter()
+            .find(|link| link.id == id)
+            .map(|link| &*link.slot)
+    }
+
+    /// Remove hook `id` from the chain, joining up the hooks around it, and return its slot.
+    pub(super) fn remove(&mut self, id: usize) -> Result<Option<Box<HookSlot>>> {
+        let Some(position) = self.links.iter().position(|link| link.id == id) else {
+            return Ok(None);
+        };
+
+        let mut link = self.links.remove(position);
+        link.slot.pin = self.copied.clone();
+        self.relink()?;
+
+        Ok(Some(link.slot))
+    }
+
+    /// Whether hook `id` is called.
+    pub(super) fn is_enabled(&self, id: usize) -> bool {
+        self.links.iter().any(|link| link.id == id && link.enabled)
+    }
+
+    /// Set whether hook `id` is called, keeping its place in the chain. A disabled hook is skipped, so
+    /// the hook before it calls the hook after it.
+    pub(super) fn set_enabled(&mut self, id: usize, enabled: bool) -> Result<()> {
+        let Some(position) = self.links.iter().position(|link| link.id == id) else {
+            return Ok(());
+        };
+
+        if self.links[position].enabled == enabled {
+            return Ok(());
+        }
+
+        self.links[position].enabled = enabled;
+
+        if let Err(error) = self.relink() {
+            self.links[position].enabled = !enabled;
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// The slots of the hooks in the order they are called, and whether each is enabled.
+    pub(super) fn slots(&self) -> impl Iterator<Item = (&HookSlot, bool)> {
+        self.links.iter().map(|link| (&*link.slot, link.enabled))
+    }
+
+    /// The function that was in the entry before it was hooked.
+    pub(super) fn original(&self) -> *const () {
+        self.original
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    /// Keep `copy`, which was copied from the entry, pointing where the entry does, until
+    /// [`HookChain::remove_copy`]. The slots of the chain aren't freed while the returned `Arc` or a
+    /// clone of it is alive.
+    ///
+    /// # Safety
+    /// * `copy` must be valid for reads and writes until it's removed or the chain is dropped.
+    ///
+    pub(super) unsafe fn add_copy(&mut self, copy: *mut *const ()) -> Arc<()> {
+        self.copies.push(copy);
+
+        self.copied.upgrade().unwrap_or_else(|| {
+            let copied = Arc::new(());
+            self.copied = Arc::downgrade(&copied);
+            copied
+        })
+    }
+
+    /// Stop updating `copy`.
+    pub(super) fn remove_copy(&mut self, copy: *mut *const ()) {
+        self.copies.retain(|other| *other != copy);
+    }
+
+    /// Close every hook so that calls go straight through it to the next one, and never free them or
+    /// put the original function back, for when something else may still call into them.
+    pub(super) fn leak(self) {
+        for link in &self.links {
+            link.slot.close();
+        }
+
+        std::mem::forget(self);
+    }
+
+    /// Point each hook at the next enabled one, starting from the end of the chain so that every hook
+    /// that can be reached is already pointing at the right place.
+    ///
+    /// Disabled hooks are pointed at the next enabled hook too, for calls that were already in them.
+    fn relink(&self) -> Result<()> {
+        let mut next = self.original;
+
+        for link in self.links.iter().rev() {
+            link.slot.set_original(next)?;
+
+            if link.enabled {
+                next = link.slot.trampoline;
+            }
+        }
+
+        self.point_at(next)
+    }
+
+    /// Write `function` to the entry, and to the copies that still point where the entry did.
+    fn point_at(&self, function: *const ()) -> Result<()> {
+        let previous = unsafe { *self.entry };
+
+        if previous != function {
+            unsafe { write_protected(self.entry, function) }?;
+        }
+
+        for copy in &self.copies {
+            unsafe {
+                if copy.read_volatile() == previous {
+                    copy.write_volatile(function);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for HookChain {
+    fn drop(&mut self) {
+        self.point_at(self.original).unwrap();
+
+        // Calls that already have the trampoline of a hook go to the next one.
+        for mut link in self.links.drain(..) {
+            link.slot.pin = self.copied.clone();
+            link.slot.close();
+            link.slot.retire();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use crate::{call_original, call_virtual, set_vtable_len, HookFunction, HookOptions};
+
+    #[repr(C)]
+    struct Obj {
+        vtable: *const *const (),
+        value: usize,
+    }
+
+    unsafe extern "C" fn get(this: *mut Obj, a: usize) -> usize {
+        (*this).value + a
+    }
+
+    static TABLE: [unsafe extern "C" fn(*mut Obj, usize) -> usize; 1] = [get];
+
+    type Log = Arc<Mutex<Vec<&'static str>>>;
+
+    fn new_obj() -> Box<Obj> {
+        let table = TABLE.as_ptr() as *const *const ();
+        set_vtable_len(table, TABLE.len());
+
+        Box::new(Obj {
+            vtable: table,
+            value: 1,
+        })
+    }
+
+    /// Hook `obj` with a hook that logs `name` and adds 10 to the argument.
+    fn hook(obj: &mut Obj, priority: i32, name: &'static str, log: &Log) -> HookFunction {
+        let log = log.clone();
+        let options = HookOptions {
+            priority,
+            ..Default::default()
+        };
+
+        HookFunction::with_options(
+            obj,
+            0,
+            0,
+            options,
+            move |ctx, this: &mut Obj, a: usize| -> usize {
+                log.lock().push(name);
+                call_original(ctx, this, (a + 10,))
+            },
+        )
+        .unwrap()
+    }
+
+    /// Call the function, and return what it returned and the hooks that were called.
+    fn call(obj: &mut Obj, log: &Log) -> (usize, Vec<&'static str>) {
+        let ret = unsafe { call_virtual::<usize, (usize,)>(obj, 0, (0,)) };
+        (ret, std::mem::take(&mut *log.lock()))
+    }
+
+    #[test]
+    fn hooks_are_called_by_priority() {
+        let mut obj = new_obj();
+        let log = Log::default();
+
+        let _low = hook(&mut obj, -5, "low", &log);
+        let _high = hook(&mut obj, 10, "high", &log);
+        let _first = hook(&mut obj, 0, "first", &log);
+        let _second = hook(&mut obj, 0, "second", &log);
+
+        // Hooks with the same priority are called newest first.
+        assert_eq!(
+            call(&mut obj, &log),
+            (41, vec!["high", "second", "first", "low"])
+        );
+    }
+
+    #[test]
+    fn removing_a_hook_keeps_the_rest_of_the_chain() {
+        let mut obj = new_obj();
+        let log = Log::default();
+
+        let last = hook(&mut obj, 0, "last", &log);
+        let middle = hook(&mut obj, 1, "middle", &log);
+        let first = hook(&mut obj, 2, "first", &log);
+
+        drop(middle);
+        assert_eq!(call(&mut obj, &log), (21, vec!["first", "last"]));
+
+        drop(first);
+        assert_eq!(call(&mut obj, &log), (11, vec!["last"]));
+
+        drop(last);
+        assert_eq!(call(&mut obj, &log), (1, vec![]));
+        assert_eq!(obj.vtable, TABLE.as_ptr() as *const *const ());
+    }
+
+    #[test]
+    fn disabled_hooks_are_skipped() {
+        let mut obj = new_obj();
+        let log = Log::default();
+
+        let last = hook(&mut obj, 0, "last", &log);
+        let middle = hook(&mut obj, 1, "middle", &log);
+        let first = hook(&mut obj, 2, "first", &log);
+
+        middle.disable().unwrap();
+        assert!(!middle.is_enabled());
+        assert_eq!(call(&mut obj, &log), (21, vec!["first", "last"]));
+
+        first.disable().unwrap();
+        assert_eq!(call(&mut obj, &log), (11, vec!["last"]));
+
+        last.disable().unwrap();
+        assert_eq!(call(&mut obj, &log), (1, vec![]));
+
+        middle.enable().unwrap();
+        assert_eq!(call(&mut obj, &log), (11, vec!["middle"]));
+
+        first.enable().unwrap();
+        last.enable().unwrap();
+        assert!(first.is_enabled() && middle.is_enabled() && last.is_enabled());
+        assert_eq!(call(&mut obj, &log), (31, vec!["first", "middle", "last"]));
+    }
+}
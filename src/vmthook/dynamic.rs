@@ -0,0 +1,669 @@
+//! Hooks of functions whose signature is only known at runtime.
+//!
+//! The trampoline of a dynamic hook spills `this` and the arguments into a buffer of [`RawValue`]s,
+//! which the closure gets as [`RawArgs`], and the original function is called by another JIT compiled
+//! function that loads them back out of the buffer.
+
+use std::{
+    fmt,
+    ops::{Deref, Index, IndexMut},
+    str::FromStr,
+    sync::atomic::Ordering,
+};
+
+use anyhow::{anyhow, bail, Result};
+use cranelift::prelude::*;
+use cranelift_jit::JITModule;
+use cranelift_module::Module;
+
+use super::{
+    abi::{self, CallConv, CraneliftValue, FunctionKind},
+    thunk::{define_function, HookContext},
+    unwind::catch_panic,
+    HookFunction, HookOptions, HookSlot,
+};
+
+/// The type of an argument or return value of a dynamic hook.
+///
+/// Only scalars are supported, so aggregates that are passed by value can't be described. Signedness
+/// doesn't change how values are passed, so signed and unsigned integers are the same type.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RawType {
+    /// No value, which is only valid as a return type.
+    Void,
+    Ptr,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    F32,
+    F64,
+}
+
+impl RawType {
+    /// The Cranelift type of values of this type.
+    fn cranelift_type(self) -> Option<Type> {
+        match self {
+            RawType::Void => None,
+            RawType::Ptr | RawType::I64 => Some(types::I64),
+            RawType::I8 => Some(types::I8),
+            RawType::I16 => Some(types::I16),
+            RawType::I32 => Some(types::I32),
+            RawType::I128 => Some(types::I128),
+            RawType::F32 => Some(types::F32),
+            RawType::F64 => Some(types::F64),
+        }
+    }
+
+    fn cranelift_value(self) -> CraneliftValue {
+        match self.cranelift_type() {
+            Some(ty) => CraneliftValue::Scalar(AbiParam::new(ty)),
+            None => CraneliftValue::Void,
+        }
+    }
+}
+
+impl FromStr for RawType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim() {
+            "void" | "()" => RawType::Void,
+            "ptr" | "usize" | "isize" => RawType::Ptr,
+            "i8" | "u8" | "bool" => RawType::I8,
+            "i16" | "u16" => RawType::I16,
+            "i32" | "u32" | "char" => RawType::I32,
+            "i64" | "u64" => RawType::I64,
+            "i128" | "u128" => RawType::I128,
+            "f32" => RawType::F32,
+            "f64" => RawType::F64,
+            other => bail!("unknown type `{other}`"),
+        })
+    }
+}
+
+impl fmt::Display for RawType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RawType::Void => "void",
+            RawType::Ptr => "ptr",
+            RawType::I8 => "i8",
+            RawType::I16 => "i16",
+            RawType::I32 => "i32",
+            RawType::I64 => "i64",
+            RawType::I128 => "i128",
+            RawType::F32 => "f32",
+            RawType::F64 => "f64",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// The signature of a function hooked by [`HookFunction::new_dynamic`], not counting `this`.
+///
+/// It can be parsed from a string like `"(ptr, i32, f32) -> i64"`, where the return type can be left
+/// out for functions that return `void`, and `(void)` is the same as `()`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct RawSignature {
+    pub params: Vec<RawType>,
+    pub returns: RawType,
+}
+
+impl RawSignature {
+    pub fn new(params: impl Into<Vec<RawType>>, returns: RawType) -> Self {
+        Self {
+            params: params.into(),
+            returns,
+        }
+    }
+
+    /// The Cranelift values of the return type and params, with `this` first.
+    fn cranelift_values(&self) -> Result<(CraneliftValue, Vec<CraneliftValue>)> {
+        if self.params.contains(&RawType::Void) {
+            bail!("`void` can only be used as a return type");
+        }
+
+        Ok((
+            self.returns.cranelift_value(),
+            [RawType::Ptr]
+                .iter()
+                .chain(&self.params)
+                .map(|ty| ty.cranelift_value())
+                .collect(),
+        ))
+    }
+}
+
+impl FromStr for RawSignature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (params, returns) = match s.split_once("->") {
+            Some((params, returns)) => (params, returns.parse()?),
+            None => (s, RawType::Void),
+        };
+
+        let params = params
+            .trim()
+            .strip_prefix('(')
+            .and_then(|params| params.strip_suffix(')'))
+            .ok_or_else(|| anyhow!("expected the params of `{s}` to be in brackets"))?;
+
+        // `(void)` takes no params, like in C.
+        let params = if params.trim() == "void" { "" } else { params };
+
+        let params = params
+            .split(',')
+            .filter(|param| !param.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<_>>()?;
+
+        Ok(Self { params, returns })
+    }
+}
+
+impl fmt::Display for RawSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(")?;
+
+        for (i, param) in self.params.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{param}")?;
+        }
+
+        write!(f, ") -> {}", self.returns)
+    }
+}
+
+/// An argument or return value of a dynamic hook, as the bits of a value of its [`RawType`].
+///
+/// Values are zero extended to fill it, so reading a value as a smaller type than it was written as
+/// truncates it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct RawValue(pub u128);
+
+impl RawValue {
+    pub fn new(value: impl RawScalar) -> Self {
+        value.into_raw()
+    }
+
+    pub fn get<T: RawScalar>(self) -> T {
+        T::from_raw(self)
+    }
+}
+
+/// A type that can be stored in a [`RawValue`].
+pub trait RawScalar: Sized {
+    fn into_raw(self) -> RawValue;
+    fn from_raw(value: RawValue) -> Self;
+}
+
+macro_rules! raw_scalar_int {
+    ($($t:ty => $unsigned:ty),* $(,)?) => {
+        $(
+            impl RawScalar for $t {
+                fn into_raw(self) -> RawValue {
+                    RawValue(self as $unsigned as u128)
+                }
+
+                fn from_raw(value: RawValue) -> Self {
+                    value.0 as $t
+                }
+            }
+        )*
+    };
+}
+
+raw_scalar_int!(
+    u8 => u8, i8 => u8,
+    u16 => u16, i16 => u16,
+    u32 => u32, i32 => u32,
+    u64 => u64, i64 => u64,
+    u128 => u128, i128 => u128,
+    usize => usize, isize => usize,
+);
+
+impl RawScalar for bool {
+    fn into_raw(self) -> RawValue {
+        RawValue(self as u128)
+    }
+
+    fn from_raw(value: RawValue) -> Self {
+        value.0 as u8 != 0
+    }
+}
+
+impl RawScalar for f32 {
+    fn into_raw(self) -> RawValue {
+        RawValue(self.to_bits() as u128)
+    }
+
+    fn from_raw(value: RawValue) -> Self {
+        f32::from_bits(value.0 as u32)
+    }
+}
+
+impl RawScalar for f64 {
+    fn into_raw(self) -> RawValue {
+        RawValue(self.to_bits() as u128)
+    }
+
+    fn from_raw(value: RawValue) -> Self {
+        f64::from_bits(value.0 as u64)
+    }
+}
+
+impl<T> RawScalar for *const T {
+    fn into_raw(self) -> RawValue {
+        RawValue(self as usize as u128)
+    }
+
+    fn from_raw(value: RawValue) -> Self {
+        value.0 as usize as *const T
+    }
+}
+
+impl<T> RawScalar for *mut T {
+    fn into_raw(self) -> RawValue {
+        RawValue(self as usize as u128)
+    }
+
+    fn from_raw(value: RawValue) -> Self {
+        value.0 as usize as *mut T
+    }
+}
+
+/// The arguments of a call of a dynamic hook, which can be changed before calling the original
+/// function with them.
+///
+/// `this` is kept apart from the arguments, so the first argument after it is at index 0.
+#[derive(Debug)]
+pub struct RawArgs<'a> {
+    // `this` followed by the arguments.
+    values: &'a mut [RawValue],
+}
+
+impl RawArgs<'_> {
+    pub fn this(&self) -> *mut () {
+        self.values[0].get()
+    }
+
+    pub fn set_this(&mut self, this: *mut ()) {
+        self.values[0] = RawValue::new(this);
+    }
+
+    /// Get argument `index` as `T`.
+    pub fn get<T: RawScalar>(&self, index: usize) -> T {
+        self[index].get()
+    }
+
+    /// Set argument `index` to `value`.
+    pub fn set(&mut self, index: usize, value: impl RawScalar) {
+        self[index] = RawValue::new(value);
+    }
+
+    /// The number of arguments, not counting `this`.
+    pub fn len(&self) -> usize {
+        self.values.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Index<usize> for RawArgs<'_> {
+    type Output = RawValue;
+
+    fn index(&self, index: usize) -> &RawValue {
+        &self.values[1..][index]
+    }
+}
+
+impl IndexMut<usize> for RawArgs<'_> {
+    fn index_mut(&mut self, index: usize) -> &mut RawValue {
+        &mut self.values[1..][index]
+    }
+}
+
+/// Calls a function with the values in the buffer `args`, writing the result to `ret`.
+type DynamicCaller = unsafe extern "C" fn(*const (), *const RawValue, *mut RawValue);
+
+/// The context of a call of a dynamic hook.
+#[derive(Debug)]
+pub struct DynamicContext {
+    original: *const (),
+    caller: DynamicCaller,
+    hook: HookContext,
+}
+
+impl Deref for DynamicContext {
+    type Target = HookContext;
+
+    fn deref(&self) -> &HookContext {
+        &self.hook
+    }
+}
+
+impl DynamicContext {
+    /// Call the original function with `args`, which can be the arguments the hook was called with or
+    /// a changed copy of them.
+    pub fn call_original(&self, args: &RawArgs) -> RawValue {
+        let mut ret = RawValue::default();
+        unsafe { (self.caller)(self.original, args.values.as_ptr(), &mut ret) };
+        ret
+    }
+}
+
+type DynamicClosure = dyn Fn(&DynamicContext, &mut RawArgs) -> RawValue + Send + Sync + 'static;
+
+/// Called by the trampoline of a dynamic hook with the values it was called with.
+unsafe extern "C" fn dynamic_thunk(
+    slot: *const HookSlot,
+    caller: DynamicCaller,
+    len: usize,
+    args: *mut RawValue,
+    ret: *mut RawValue,
+) {
+    let slot = &*slot;
+    let _in_flight = slot.enter();
+
+    let original = slot.original.load(Ordering::Acquire) as *const ();
+
+    // The hook is being removed or skips this call, so don't call the closure.
+    if slot.passes_through() {
+        caller(original, args, ret);
+        return;
+    }
+
+    let closure = std::mem::transmute::<*const dyn Fn(), *const DynamicClosure>(slot.closure);
+
+    let context = DynamicContext {
+        original,
+        caller,
+        hook: HookContext::new(slot),
+    };
+    let values = args;
+    let mut args = RawArgs {
+        values: std::slice::from_raw_parts_mut(values, len),
+    };
+
+    *ret = slot.timed(|| {
+        catch_panic(
+            slot,
+            || (*closure)(&context, &mut args),
+            || {
+                let mut ret = RawValue::default();
+                caller(original, values, &mut ret);
+                ret
+            },
+            |value| value,
+        )
+    });
+    slot.unhook_if_requested();
+}
+
+/// Dynamic hooks call the original function with its own calling convention, so they don't need a
+/// bridge.
+unsafe fn no_original_bridge(
+    _closure: *const dyn Fn(),
+    _module: &mut JITModule,
+    _call_conv: CallConv,
+    _original: *const (),
+) -> Result<Option<*const ()>> {
+    Ok(None)
+}
+
+/// Size of each value in the buffers of dynamic hooks.
+const VALUE_SIZE: i32 = std::mem::size_of::<RawValue>() as i32;
+
+/// Make a stack slot for `len` raw values that are all zero, and return its address.
+fn zeroed_values(builder: &mut FunctionBuilder, len: usize) -> Value {
+    let slot = builder.create_sized_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        len as u32 * VALUE_SIZE as u32,
+        VALUE_SIZE.trailing_zeros() as u8,
+    ));
+    let address = builder.ins().stack_addr(types::I64, slot, 0);
+
+    let zero = builder.ins().iconst(types::I64, 0);
+    for offset in (0..len as i32 * VALUE_SIZE).step_by(8) {
+        builder
+            .ins()
+            .store(MemFlags::trusted(), zero, address, offset);
+    }
+
+    address
+}
+
+/// Make the trampoline of a dynamic hook, which spills its params into a buffer and calls
+/// [`dynamic_thunk`] with it, and the caller it uses to call the original function.
+fn make_trampoline(
+    module: &mut JITModule,
+    slot: *const (),
+    signature: &RawSignature,
+    call_conv: CallConv,
+) -> Result<*const ()> {
+    let host_call_conv = module.isa().default_call_conv();
+    let (returns, params) = signature.cranelift_values()?;
+    let return_type = signature.returns.cranelift_type();
+
+    // Every value is a scalar, so each one is a single Cranelift value.
+    let original_sig = abi::lower_signature(
+        call_conv.isa_call_conv(host_call_conv),
+        FunctionKind::Member,
+        &returns,
+        &params,
+    )?;
+
+    let mut caller_sig = Signature::new(host_call_conv);
+    caller_sig.params = vec![AbiParam::new(types::I64); 3];
+
+    let caller = define_function(module, &caller_sig, |builder, caller_params| {
+        let [original, args, ret] = caller_params else {
+            unreachable!()
+        };
+
+        let values = original_sig
+            .signature
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| {
+                builder.ins().load(
+                    param.value_type,
+                    MemFlags::trusted(),
+                    *args,
+                    i as i32 * VALUE_SIZE,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let original_sig_ref = builder.import_signature(original_sig.signature.clone());
+        let call = builder
+            .ins()
+            .call_indirect(original_sig_ref, *original, &values);
+
+        if let Some(result) = builder.inst_results(call).first().copied() {
+            builder.ins().store(MemFlags::trusted(), result, *ret, 0);
+        }
+
+        builder.ins().return_(&[]);
+    })?;
+
+    let mut thunk_sig = Signature::new(host_call_conv);
+    thunk_sig.params = vec![AbiParam::new(types::I64); 5];
+
+    define_function(module, &original_sig.signature, |builder, values| {
+        let args = zeroed_values(builder, values.len());
+        let ret = zeroed_values(builder, 1);
+
+        for (i, value) in values.iter().enumerate() {
+            builder
+                .ins()
+                .store(MemFlags::trusted(), *value, args, i as i32 * VALUE_SIZE);
+        }
+
+        let const_slot = builder.ins().iconst(types::I64, slot as i64);
+        let const_caller = builder.ins().iconst(types::I64, caller as i64);
+        let const_len = builder.ins().iconst(types::I64, values.len() as i64);
+        let thunk = builder
+            .ins()
+            .iconst(types::I64, dynamic_thunk as *const () as i64);
+
+        let thunk_sig_ref = builder.import_signature(thunk_sig.clone());
+        builder.ins().call_indirect(
+            thunk_sig_ref,
+            thunk,
+            &[const_slot, const_caller, const_len, args, ret],
+        );
+
+        let returns = match return_type {
+            Some(ty) => vec![builder.ins().load(ty, MemFlags::trusted(), ret, 0)],
+            None => vec![],
+        };
+
+        builder.ins().return_(&returns);
+    })
+}
+
+impl HookFunction {
+    /// Hook a function whose signature is only known at runtime, see [`HookFunction::new`].
+    ///
+    /// The closure is called with the arguments spilled into [`RawArgs`], and returns the return value
+    /// of the function as a [`RawValue`], which is ignored if it returns `void`. The original function
+    /// can be called with the arguments, changed or not, by [`DynamicContext::call_original`].
+    ///
+    /// ```rs
+    /// let signature = "(ptr, i32, f32) -> i64".parse()?;
+    ///
+    /// let hook = HookFunction::new_dynamic(instance, 0, 14, signature, |ctx, args| {
+    ///     args.set(1, args.get::<i32>(1) * 2);
+    ///     ctx.call_original(args)
+    /// })?;
+    /// ```
+    ///
+    pub fn new_dynamic(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        signature: RawSignature,
+        f: impl Fn(&DynamicContext, &mut RawArgs) -> RawValue + Send + Sync + 'static,
+    ) -> Result<Self> {
+        Self::dynamic_with_options(
+            instance,
+            offset,
+            index,
+            HookOptions::default(),
+            signature,
+            f,
+        )
+    }
+
+    /// Like [`HookFunction::new_dynamic`], with the [`HookOptions`] in `options`.
+    pub fn dynamic_with_options<F>(
+        instance: *mut impl Sized,
+        offset: usize,
+        index: usize,
+        options: HookOptions,
+        signature: RawSignature,
+        f: F,
+    ) -> Result<Self>
+    where
+        F: Fn(&DynamicContext, &mut RawArgs) -> RawValue + Send + Sync + 'static,
+    {
+        Self::with_slot(instance, offset, index, options.priority, || {
+            let closure = Box::new(f) as Box<DynamicClosure>;
+            let closure = unsafe {
+                std::mem::transmute::<*const DynamicClosure, *const dyn Fn()>(Box::into_raw(
+                    closure,
+                ))
+            };
+
+            HookSlot::with_trampoline(
+                closure,
+                super::drop_closure::<F>,
+                no_original_bridge,
+                std::any::type_name::<F>(),
+                options,
+                |module, slot| make_trampoline(module, slot, &signature, options.call_conv),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_round_trips() {
+        for s in ["(ptr, i32, f32) -> i64", "() -> void", "(i128) -> f64"] {
+            let signature = s.parse::<RawSignature>().unwrap();
+
+            assert_eq!(signature.to_string(), s);
+            assert_eq!(
+                signature.to_string().parse::<RawSignature>().unwrap(),
+                signature
+            );
+        }
+    }
+
+    #[test]
+    fn signature_ignores_whitespace() {
+        let signature = "  ( usize ,u8,  f32 )->  u64 "
+            .parse::<RawSignature>()
+            .unwrap();
+
+        assert_eq!(
+            signature,
+            RawSignature::new([RawType::Ptr, RawType::I8, RawType::F32], RawType::I64)
+        );
+        assert_eq!(
+            "( )".parse::<RawSignature>().unwrap(),
+            RawSignature::new([], RawType::Void)
+        );
+    }
+
+    #[test]
+    fn signature_without_return_type_returns_void() {
+        let signature = "(i32, ptr)".parse::<RawSignature>().unwrap();
+
+        assert_eq!(
+            signature,
+            RawSignature::new([RawType::I32, RawType::Ptr], RawType::Void)
+        );
+        assert!("i32, ptr".parse::<RawSignature>().is_err());
+        assert!("(i32) i64".parse::<RawSignature>().is_err());
+    }
+
+    #[test]
+    fn void_params_are_empty() {
+        assert_eq!(
+            "(void) -> i32".parse::<RawSignature>().unwrap(),
+            RawSignature::new([], RawType::I32)
+        );
+        assert_eq!(
+            "( void )".parse::<RawSignature>().unwrap(),
+            RawSignature::new([], RawType::Void)
+        );
+
+        // `void` can't be a param alongside others.
+        let signature = "(i32, void)".parse::<RawSignature>().unwrap();
+        assert!(signature.cranelift_values().is_err());
+    }
+
+    #[test]
+    fn unknown_types_are_errors() {
+        let error = "(i32, string) -> i32".parse::<RawSignature>().unwrap_err();
+        assert_eq!(error.to_string(), "unknown type `string`");
+
+        let error = "(i32) -> int".parse::<RawSignature>().unwrap_err();
+        assert_eq!(error.to_string(), "unknown type `int`");
+    }
+}
@@ -0,0 +1,185 @@
+//! Listing the hooks that are installed, for debugging and profiling them.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use super::{HookInstance, HookSlot, VtableHook};
+
+/// An installed hook, from [`active_hooks`].
+#[derive(Clone, Debug)]
+pub struct HookInfo {
+    /// The hooked instance, or `None` for a [`crate::VtableHook`].
+    pub instance: Option<*mut ()>,
+    /// The offset of the vtable pointer from the instance, which is 0 for a [`crate::VtableHook`].
+    pub offset: usize,
+    /// The original vtable of the instance, or the hooked vtable of a [`crate::VtableHook`].
+    pub vtable: *const *const (),
+    /// The index of the hooked function in the vtable.
+    pub index: usize,
+    /// The name of the type of the closure, such as `my_crate::install::{{closure}}`.
+    pub closure_type: &'static str,
+    /// Whether the closure is called, see [`crate::HookFunction::disable`].
+    pub enabled: bool,
+    /// The function that was hooked.
+    pub original: *const (),
+    /// Where the function that was hooked is, if it's in a module.
+    pub original_location: Option<ModuleLocation>,
+    /// The counters of the hook, if it was made with [`crate::HookOptions::collect_stats`].
+    pub stats: Option<HookStats>,
+}
+
+unsafe impl Send for HookInfo {}
+unsafe impl Sync for HookInfo {}
+
+/// An address in a loaded module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleLocation {
+    /// The path of the module.
+    pub module: String,
+    /// The offset of the address from the base of the module.
+    pub rva: usize,
+}
+
+/// The calls of the closure of a hook that collects stats. The time spent in a call includes the
+/// time spent in the hooks after it and the original function, when the closure calls them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HookStats {
+    pub calls: u64,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+/// The counters behind [`HookStats`], which are updated by the thunk.
+#[derive(Debug, Default)]
+pub(super) struct HookCounters {
+    calls: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl HookCounters {
+    /// Count a call that took `elapsed`.
+    pub(super) fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().try_into().unwrap_or(u64::MAX);
+
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> HookStats {
+        HookStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            total_time: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            max_time: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Every installed [`crate::HookFunction`] and [`crate::VtableHook`], including disabled ones, in the
+/// order they are called for each hooked function.
+///
+/// The hooks made by [`HookInstance::track_destructor`] are listed too.
+pub fn active_hooks() -> Vec<HookInfo> {
+    let mut hooks = vec![];
+
+    for hook in HookInstance::hooked_instances() {
+        let hook = hook.lock();
+
+        for chain in hook.chains.values() {
+            hooks.extend(chain.slots().map(|(slot, enabled)| {
+                let original = slot
+                    .original_function(slot.target().index)
+                    .unwrap_or_else(|| chain.original());
+
+                info(slot, enabled, hook.offset, original)
+            }));
+        }
+    }
+
+    for chain in VtableHook::chains().values() {
+        hooks.extend(
+            chain
+                .slots()
+                .map(|(slot, enabled)| info(slot, enabled, 0, chain.original())),
+        );
+    }
+
+    hooks
+}
+
+fn info(slot: &HookSlot, enabled: bool, offset: usize, original: *const ()) -> HookInfo {
+    let target = slot.target();
+
+    HookInfo {
+        instance: (!target.instance.is_null()).then_some(target.instance),
+        offset,
+        vtable: target.original_table,
+        index: target.index,
+        closure_type: slot.closure_type,
+        enabled,
+        original,
+        original_location: module_location(original),
+        stats: slot.stats.as_ref().map(HookCounters::get),
+    }
+}
+
+/// The module that `address` is in, and its offset in it.
+#[cfg(target_os = "windows")]
+fn module_location(address: *const ()) -> Option<ModuleLocation> {
+    use windows::{
+        core::PCWSTR,
+        Win32::{
+            Foundation::HMODULE,
+            System::LibraryLoader::{
+                GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+                GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            },
+        },
+    };
+
+    let mut module = HMODULE::default();
+
+    unsafe {
+        GetModuleHandleExW(
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            PCWSTR(address as *const u16),
+            &mut module,
+        )
+    }
+    .ok()?;
+
+    let mut path = [0u16; 1024];
+    let len = unsafe { GetModuleFileNameW(module, &mut path) } as usize;
+
+    Some(ModuleLocation {
+        module: String::from_utf16_lossy(&path[..len]),
+        rva: address as usize - module.0 as usize,
+    })
+}
+
+/// The module that `address` is in, and its offset in it.
+#[cfg(unix)]
+fn module_location(address: *const ()) -> Option<ModuleLocation> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::dladdr(address as *const libc::c_void, &mut info) } == 0
+        || info.dli_fname.is_null()
+    {
+        return None;
+    }
+
+    let module = unsafe { std::ffi::CStr::from_ptr(info.dli_fname) };
+
+    Some(ModuleLocation {
+        module: module.to_string_lossy().into_owned(),
+        rva: address as usize - info.dli_fbase as usize,
+    })
+}
+
+#[cfg(not(any(target_os = "windows", unix)))]
+fn module_location(_address: *const ()) -> Option<ModuleLocation> {
+    None
+}
@@ -0,0 +1,271 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use parking_lot::{Mutex, MutexGuard};
+
+use super::{chain::HookChain, HookFunction, HookInstance, VtableHook};
+
+/// A hook owned by a [`HookSet`].
+#[derive(Debug)]
+enum SetHook {
+    Function(HookFunction),
+    Vtable(VtableHook),
+}
+
+/// The chains of the hooks in a set, which are locked for as long as this is alive.
+struct LockedChains<'a> {
+    instances: Vec<(*const Mutex<HookInstance>, MutexGuard<'a, HookInstance>)>,
+    vtables: MutexGuard<'static, HashMap<usize, HookChain>>,
+}
+
+impl<'a> LockedChains<'a> {
+    /// Lock the chains of `hooks`. Instances are locked in order of address, and before the vtable
+    /// hooks, so that sets with hooks in common don't deadlock.
+    fn lock(hooks: &'a [SetHook]) -> Self {
+        let mut instances = hooks
+            .iter()
+            .filter_map(|hook| match hook {
+                SetHook::Function(hook) => Some(&hook.instance_hook),
+                SetHook::Vtable(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        instances.sort_by_key(|instance| Arc::as_ptr(instance));
+        instances.dedup_by_key(|instance| Arc::as_ptr(instance));
+
+        Self {
+            instances: instances
+                .into_iter()
+                .map(|instance| (Arc::as_ptr(instance), instance.lock()))
+                .collect(),
+            vtables: VtableHook::chains(),
+        }
+    }
+
+    /// The chain that `hook` is in, and the id of the hook in it.
+    fn chain(&mut self, hook: &SetHook) -> Option<(&mut HookChain, usize)> {
+        match hook {
+            SetHook::Function(hook) => {
+                let (_, instance) = self
+                    .instances
+                    .iter_mut()
+                    .find(|(instance, _)| *instance == Arc::as_ptr(&hook.instance_hook))?;
+
+                Some((instance.chains.get_mut(&hook.index)?, hook.id))
+            }
+            SetHook::Vtable(hook) => Some((self.vtables.get_mut(&(hook.entry as usize))?, hook.id)),
+        }
+    }
+
+    fn is_enabled(&mut self, hook: &SetHook) -> bool {
+        self.chain(hook)
+            .is_some_and(|(chain, id)| chain.is_enabled(id))
+    }
+}
+
+/// A group of hooks that are enabled and disabled together, such as the hooks of one feature.
+///
+/// The hooks are unhooked when the set is dropped.
+#[derive(Debug, Default)]
+pub struct HookSet {
+    hooks: Vec<SetHook>,
+}
+
+impl HookSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `hook` to the set. It stays enabled or disabled until the set is next changed.
+    pub fn add_function(&mut self, hook: HookFunction) {
+        self.hooks.push(SetHook::Function(hook));
+    }
+
+    /// Add `hook` to the set. It stays enabled or disabled until the set is next changed.
+    pub fn add_vtable_hook(&mut self, hook: VtableHook) {
+        self.hooks.push(SetHook::Vtable(hook));
+    }
+
+    /// Enable every hook in the set, see [`HookSet::set_enabled`].
+    pub fn enable(&self) -> Result<()> {
+        self.set_enabled(true)
+    }
+
+    /// Disable every hook in the set, see [`HookSet::set_enabled`].
+    pub fn disable(&self) -> Result<()> {
+        self.set_enabled(false)
+    }
+
+    /// Enable or disable every hook in the set.
+    ///
+    /// If any of the hooks can't be changed, the ones that already were are changed back, so either
+    /// all of the hooks are changed or none of them are.
+    ///
+    /// The hooks are locked until all of them have been changed, so other changes to them and
+    /// [`HookSet::is_enabled`] never see the set half changed. Calls of the hooked functions on other
+    /// threads can still see some of the hooks changed before others, as each function is changed
+    /// separately.
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        let mut chains = LockedChains::lock(&self.hooks);
+
+        let previous = self
+            .hooks
+            .iter()
+            .map(|hook| chains.is_enabled(hook))
+            .collect::<Vec<_>>();
+
+        for (i, hook) in self.hooks.iter().enumerate() {
+            let Some((chain, id)) = chains.chain(hook) else {
+                continue;
+            };
+
+            if let Err(error) = chain.set_enabled(id, enabled) {
+                for (hook, &was_enabled) in self.hooks[..i].iter().zip(&previous) {
+                    // This is only putting back entries that were just written, so it can't fail
+                    // where the write before it didn't.
+                    if let Some((chain, id)) = chains.chain(hook) {
+                        let _ = chain.set_enabled(id, was_enabled);
+                    }
+                }
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether every hook in the set is enabled.
+    pub fn is_enabled(&self) -> bool {
+        let mut chains = LockedChains::lock(&self.hooks);
+
+        self.hooks.iter().all(|hook| chains.is_enabled(hook))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{call_original, call_virtual, set_vtable_len};
+
+    #[repr(C)]
+    struct Obj {
+        vtable: *const *const (),
+        value: usize,
+    }
+
+    unsafe extern "C" fn get(this: *mut Obj, a: usize) -> usize {
+        (*this).value + a
+    }
+
+    static TABLE: [unsafe extern "C" fn(*mut Obj, usize) -> usize; 1] = [get];
+
+    fn new_obj(vtable: *const *const ()) -> Box<Obj> {
+        set_vtable_len(TABLE.as_ptr() as *const *const (), TABLE.len());
+
+        Box::new(Obj { vtable, value: 1 })
+    }
+
+    fn call(obj: &mut Obj) -> usize {
+        unsafe { call_virtual::<usize, (usize,)>(obj, 0, (0,)) }
+    }
+
+    /// Hook `obj` with a hook that adds `n` to what the function returns.
+    fn hook(obj: &mut Obj, n: usize) -> HookFunction {
+        HookFunction::new(obj, 0, 0, move |ctx, this: &mut Obj, a: usize| -> usize {
+            call_original(ctx, this, (a,)) + n
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn hooks_are_changed_together() {
+        let mut a = new_obj(TABLE.as_ptr() as *const *const ());
+        let mut b = new_obj(TABLE.as_ptr() as *const *const ());
+
+        let mut set = HookSet::new();
+        set.add_function(hook(&mut a, 10));
+        set.add_function(hook(&mut b, 20));
+        assert!(set.is_enabled());
+
+        set.disable().unwrap();
+        assert!(!set.is_enabled());
+        assert_eq!((call(&mut a), call(&mut b)), (1, 1));
+
+        set.enable().unwrap();
+        assert!(set.is_enabled());
+        assert_eq!((call(&mut a), call(&mut b)), (11, 21));
+    }
+
+    /// Map `fd` at `address`, or anywhere if it's null, and return where it was mapped.
+    #[cfg(unix)]
+    unsafe fn map(fd: &std::fs::File, address: *mut libc::c_void, len: usize) -> *mut *const () {
+        use std::os::fd::AsRawFd;
+
+        let flags = if address.is_null() {
+            libc::MAP_SHARED
+        } else {
+            libc::MAP_SHARED | libc::MAP_FIXED
+        };
+        let mapped = libc::mmap(address, len, libc::PROT_READ, flags, fd.as_raw_fd(), 0);
+        assert_ne!(mapped, libc::MAP_FAILED);
+
+        mapped as *mut *const ()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_changes_are_undone() {
+        let len = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let path = std::env::temp_dir().join(format!("scan-set-test-{}", std::process::id()));
+
+        let mut contents = vec![0; len];
+        contents[..size_of::<usize>()].copy_from_slice(&(get as *const () as usize).to_ne_bytes());
+        std::fs::write(&path, contents).unwrap();
+
+        let writable = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let read_only = std::fs::File::open(&path).unwrap();
+        let table = unsafe { map(&writable, std::ptr::null_mut(), len) };
+
+        let mut a = new_obj(TABLE.as_ptr() as *const *const ());
+        let mut b = new_obj(table);
+
+        let mut set = HookSet::new();
+        set.add_function(hook(&mut a, 10));
+        set.add_vtable_hook(
+            unsafe {
+                VtableHook::new(table, 0, |ctx, this: &mut Obj, a: usize| -> usize {
+                    call_original(ctx, this, (a,)) + 100
+                })
+            }
+            .unwrap(),
+        );
+        assert_eq!((call(&mut a), call(&mut b)), (11, 101));
+
+        // The vtable can't be made writable while it's mapped from a read only file, so disabling
+        // the vtable hook fails after the other hook was disabled.
+        unsafe { map(&read_only, table as *mut _, len) };
+        assert!(set.disable().is_err());
+        assert!(set.is_enabled());
+        assert_eq!((call(&mut a), call(&mut b)), (11, 101));
+
+        unsafe { map(&writable, table as *mut _, len) };
+        set.disable().unwrap();
+        assert_eq!((call(&mut a), call(&mut b)), (1, 1));
+
+        drop(set);
+        unsafe { libc::munmap(table as *mut _, len) };
+        std::fs::remove_file(path).unwrap();
+    }
+}
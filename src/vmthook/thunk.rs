@@ -1,11 +1,17 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{Linkage, Module};
+use cranelift_module::Module;
 use parking_lot::{Mutex, MutexGuard};
-use std::sync::Arc;
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
 
-use super::HookInstance;
+use super::abi::{self, AsCraneliftValue, CallConv, CraneliftValue, FunctionKind};
+use super::call::OriginalFunction;
+use super::HookSlot;
 
 pub trait ThunkableClosure<R, T, Args>
 where
@@ -20,67 +26,131 @@ where
     ///
     fn thunk(&self) -> *const ();
 
-    fn unique_name(&self) -> String {
-        format!("trampoline_{:?}", std::any::TypeId::of::<Self>())
-    }
-
-    /// This is the signature of the rust thunk that is returned from [`Self::thunk`]
-    fn thunk_cranelift_sig(&self, module: &mut JITModule) -> cranelift::prelude::Signature;
+    /// The return value and params (`this` followed by the arguments) of the original function.
+    fn cranelift_values(&self) -> (CraneliftValue, Vec<CraneliftValue>);
 
-    /// This is the signature of the original function.
-    fn original_cranelift_sig(&self, module: &mut JITModule) -> cranelift::prelude::Signature;
+    /// Make a trampoline for this closure, which is called with `call_conv`. This closure has the
+    /// address of the thunk and the slot of the hook baked in.
+    ///
+    /// Trampolines aren't cached or shared between hooks, even ones with the same closure type,
+    /// index and calling convention, as each one has its own slot baked in. A shared trampoline
+    /// would only get the hooked instance, and would have to look its slot up on every call, which
+    /// the slot is there to avoid.
+    fn make_trampoline(
+        &self,
+        module: &mut JITModule,
+        slot: *const (),
+        call_conv: CallConv,
+    ) -> Result<*const ()> {
+        let host_call_conv = module.isa().default_call_conv();
+        let (returns, params) = self.cranelift_values();
 
-    /// Make a trampoline for this closure. This closure has the address of the thunk and the id
-    /// baked in.
-    fn make_trampoline(&self, module: &mut JITModule, id: usize) -> Result<*const ()> {
         // Signature of the trampoline that we are going to be swapping into place of the original fn
-        let original_sig = { self.original_cranelift_sig(module) };
+        let original_sig = abi::lower_signature(
+            call_conv.isa_call_conv(host_call_conv),
+            FunctionKind::Member,
+            &returns,
+            &params,
+        )?;
+
+        // Signature of the rust thunk, which takes the slot followed by the original params
+        let thunk_params = [vec![cranelift_value::<*const ()>()], params].concat();
+        let thunk_sig =
+            abi::lower_signature(host_call_conv, FunctionKind::Free, &returns, &thunk_params)?;
+
+        define_function(module, &original_sig.signature, |builder, params| {
+            // Bake in the slot and the pointer to thunk.
+            let const_slot = builder.ins().iconst(types::I64, slot as i64);
+            let thunk_id = builder.ins().iconst(types::I64, self.thunk() as i64);
 
-        // Signature of the rust thunk
-        let thunk_sig = { self.thunk_cranelift_sig(module) };
+            // Call the thunk with the slot, followed by this, followed by $($Args,)*, and return
+            // whatever it returns. Values are moved around if the thunk expects them elsewhere.
+            let thunk_sig_ref = builder.import_signature(thunk_sig.signature.clone());
 
-        let mut ctx = module.make_context();
-        let mut fn_builder_ctx = FunctionBuilderContext::new();
-        ctx.func.signature = original_sig;
+            abi::forward_call(
+                builder,
+                (&original_sig, params),
+                (&thunk_sig, thunk_sig_ref, thunk_id),
+                &[const_slot],
+            );
+        })
+    }
 
-        {
-            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fn_builder_ctx);
-            let block = builder.create_block();
-            // Make our function params the entry block params
-            builder.append_block_params_for_function_params(block);
-            builder.switch_to_block(block);
-            builder.seal_block(block);
+    /// Make a function that can be called from rust like any other member function, which calls
+    /// `original` with `call_conv`.
+    ///
+    /// Returns `None` if `call_conv` is the same as the host's, and `original` can be called directly.
+    fn make_original_bridge(
+        &self,
+        module: &mut JITModule,
+        call_conv: CallConv,
+        original: *const (),
+    ) -> Result<Option<*const ()>> {
+        let host_call_conv = module.isa().default_call_conv();
+        let call_conv = call_conv.isa_call_conv(host_call_conv);
+
+        if call_conv == host_call_conv {
+            return Ok(None);
+        }
 
-            // Bake in the id and the pointer to thunk.
-            let const_id = builder.ins().iconst(types::I64, id as i64);
-            let thunk_id = builder.ins().iconst(types::I64, self.thunk() as i64);
+        let (returns, params) = self.cranelift_values();
 
-            // Build up the params that we are going to pass to the thunk
-            // This is the id, followed by this, followed by $($Args,)*
-            let params = builder.block_params(block);
-            let mut call_args = vec![const_id];
-            call_args.extend_from_slice(params);
+        let bridge_sig =
+            abi::lower_signature(host_call_conv, FunctionKind::Member, &returns, &params)?;
+        let original_sig =
+            abi::lower_signature(call_conv, FunctionKind::Member, &returns, &params)?;
 
-            // Call the thunk
-            let thunk_sig = builder.import_signature(thunk_sig);
-            let call = builder.ins().call_indirect(thunk_sig, thunk_id, &call_args);
+        let bridge = define_function(module, &bridge_sig.signature, |builder, params| {
+            let original = builder.ins().iconst(types::I64, original as i64);
+            let original_sig_ref = builder.import_signature(original_sig.signature.clone());
 
-            // Return whatever thunk returns
-            let result = builder.inst_results(call)[0];
-            builder.ins().return_(&[result]);
-        }
+            abi::forward_call(
+                builder,
+                (&bridge_sig, params),
+                (&original_sig, original_sig_ref, original),
+                &[],
+            );
+        })?;
 
-        let trampoline_id =
-            module.declare_function(&self.unique_name(), Linkage::Export, &ctx.func.signature)?;
+        Ok(Some(bridge))
+    }
+}
 
-        module.define_function(trampoline_id, &mut ctx)?;
+/// Define an anonymous function in `module` with `signature`, whose body is built by `build` from the
+/// function params, and return a pointer to its code.
+///
+/// The functions are anonymous as every hook gets its own, even if it uses the same closure type as
+/// another one, see [`ThunkableClosure::make_trampoline`].
+pub(super) fn define_function(
+    module: &mut JITModule,
+    signature: &Signature,
+    build: impl FnOnce(&mut FunctionBuilder, &[Value]),
+) -> Result<*const ()> {
+    let mut ctx = module.make_context();
+    let mut fn_builder_ctx = FunctionBuilderContext::new();
+    ctx.func.signature = signature.clone();
+
+    {
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fn_builder_ctx);
+        let block = builder.create_block();
+        // Make our function params the entry block params
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        builder.seal_block(block);
+
+        let params = builder.block_params(block).to_vec();
+        build(&mut builder, &params);
+    }
 
-        module.clear_context(&mut ctx);
-        module.finalize_definitions()?;
+    let function_id = module.declare_anonymous_function(&ctx.func.signature)?;
 
-        let code_ptr = module.get_finalized_function(trampoline_id);
-        Ok(unsafe { std::mem::transmute::<*const u8, *const ()>(code_ptr) })
-    }
+    module.define_function(function_id, &mut ctx)?;
+
+    module.clear_context(&mut ctx);
+    module.finalize_definitions()?;
+
+    let code_ptr = module.get_finalized_function(function_id);
+    Ok(unsafe { std::mem::transmute::<*const u8, *const ()>(code_ptr) })
 }
 
 use paste::paste;
@@ -90,13 +160,71 @@ pub trait Call<R: 'static, T: 'static, Args: 'static> {
     fn call(&self, this: &mut T, args: Args) -> R;
 }
 
+/// [`VirtualArgs`] is implemented for tuples of [`AsCraneliftValue`] arguments returning an
+/// [`AsCraneliftValue`], and is used by [`crate::call_virtual`] to call a function pointer with them.
+pub trait VirtualArgs<R: 'static>: 'static {
+    /// Call `function` with `this` followed by the arguments in `self`.
+    ///
+    /// # Safety
+    /// * `function` must be an `extern "C"` function taking `this` and then `Self`, returning `R`.
+    ///
+    unsafe fn call_function(self, function: *const (), this: *mut ()) -> R;
+}
+
+/// [`ObservableArgs`] is implemented for tuples of arguments, and makes the closures of
+/// [`crate::HookFunction::before`] and [`crate::HookFunction::after`] hooks, which always call the
+/// original function.
+pub trait ObservableArgs<R: 'static, T: 'static>: Sized + 'static {
+    /// Make a closure that calls `f` with the arguments, which it can change, and then calls the
+    /// original function with them.
+    fn before(
+        f: impl Fn(&mut T, &mut Self) + Send + Sync + 'static,
+    ) -> impl ThunkableClosure<R, T, Self>;
+
+    /// Make a closure that calls the original function, and then calls `f` with the arguments and the
+    /// return value, which it can change.
+    fn after(
+        f: impl Fn(&mut T, &Self, &mut R) + Send + Sync + 'static,
+    ) -> impl ThunkableClosure<R, T, Self>
+    where
+        Self: Clone;
+}
+
+/// [`StatefulClosure`] is implemented for closures that take the state of their hook after the
+/// context, and makes the closures of [`crate::HookFunction::with_state`].
+///
+/// The closure is only called while the state is locked, so it can be `FnMut`.
+pub trait StatefulClosure<S, R: 'static, T: 'static, Args: 'static>: Send + 'static {
+    type Closure: ThunkableClosure<R, T, Args>;
+
+    /// Make a closure that locks `state` and calls this closure with it.
+    fn with_state(self, state: Arc<Mutex<S>>) -> Self::Closure;
+}
+
+/// The closure of a [`StatefulClosure`], which is only called while the state of its hook is locked.
+struct LockedClosure<F>(UnsafeCell<F>);
+
+// The closure is only used through `with`, by one thread at a time.
+unsafe impl<F: Send> Sync for LockedClosure<F> {}
+
+impl<F> LockedClosure<F> {
+    /// Call `call` with the closure.
+    ///
+    /// # Safety
+    /// * The state of the hook must be locked until `call` returns.
+    ///
+    unsafe fn with<R>(&self, call: impl FnOnce(&mut F) -> R) -> R {
+        call(&mut *self.0.get())
+    }
+}
+
 /// impl_func implements [`ThunkableClosure`] for any number of parameters.
 /// In addition it also creates some other types and structs that are used in the call to the closure:
 ///
-/// * `_FuncContext<A...>` which holds the hook instance and the original function pointer.
+/// * `_FuncContext<A...>` which holds the original function pointer.
 ///
 /// It also implements [`Call`] for the `_FuncContext<A...>` type, such that you can pass it to
-///  [`call_original`].
+///  [`call_original`], and [`VirtualArgs`] for the `(A...)` tuple.
 ///
 /// ```rs
 /// // ...
@@ -115,10 +243,49 @@ macro_rules! impl_func {
             type [<_RawFunc $($Args )*>]<TRet, TThis, $($Args,)*> =
                 unsafe extern "C" fn (*mut TThis, $($Args,)*) -> TRet;
 
+            /// Call the member function `function` from rust, passing the return pointer explicitly
+            /// where the member function ABI doesn't match `extern "C"`.
+            #[allow(non_snake_case, clippy::too_many_arguments)]
+            unsafe fn [<_call_member $($Args )*>]<TRet, TThis, $($Args,)*>(
+                function: *const (),
+                this: *mut TThis,
+                $($args: $Args,)*
+            ) -> TRet
+            where
+                TRet: AsCraneliftValue,
+            {
+                if abi::member_returns_indirectly::<TRet>() {
+                    let function = std::mem::transmute::<
+                        *const (),
+                        unsafe extern "C" fn (*mut TThis, *mut TRet, $($Args,)*) -> *mut TRet,
+                    >(function);
+
+                    let mut result = std::mem::MaybeUninit::<TRet>::uninit();
+                    function(this, result.as_mut_ptr(), $($args,)*);
+                    result.assume_init()
+                } else {
+                    let function = std::mem::transmute::<
+                        *const (),
+                        [<_RawFunc $($Args )*>]<TRet, TThis, $($Args,)*>,
+                    >(function);
+
+                    function(this, $($args,)*)
+                }
+            }
+
             pub struct [<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*> {
                 pub original_fn: [<_RawFunc $($Args )*>]<TRet, TThis, $($Args,)*>,
-                #[allow(dead_code)]
-                hook_instance: Arc<Mutex<HookInstance>>,
+                hook: HookContext,
+            }
+
+            impl<TRet, TThis, $($Args,)*> std::ops::Deref
+                for [<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>
+            {
+                type Target = HookContext;
+
+                fn deref(&self) -> &HookContext {
+                    &self.hook
+                }
             }
 
             impl<
@@ -128,7 +295,7 @@ macro_rules! impl_func {
             >
             Call<TRet, TThis, ($($Args,)*)> for &[<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>
             where
-                TRet: 'static,
+                TRet: 'static + AsCraneliftValue,
                 TThis: 'static,
                 $($Args: 'static,)*
             {
@@ -138,7 +305,129 @@ macro_rules! impl_func {
                     args: ($($Args,)*)
                 ) -> TRet {
                     let ($($args,)*) = args;
-                    unsafe { (self.original_fn)(this as *mut TThis, $($args,)*) }
+                    unsafe {
+                        [<_call_member $($Args )*>](
+                            self.original_fn as *const (),
+                            this as *mut TThis,
+                            $($args,)*
+                        )
+                    }
+                }
+            }
+
+            impl<TRet, $($Args,)*> VirtualArgs<TRet> for ($($Args,)*)
+            where
+                TRet: 'static + AsCraneliftValue,
+                $($Args: 'static + AsCraneliftValue,)*
+            {
+                unsafe fn call_function(self, function: *const (), this: *mut ()) -> TRet {
+                    let ($($args,)*) = self;
+                    [<_call_member $($Args )*>](function, this, $($args,)*)
+                }
+            }
+
+            impl<TRet, TThis, $($Args,)*> ObservableArgs<TRet, TThis> for ($($Args,)*)
+            where
+                TRet: 'static + AsCraneliftValue,
+                TThis: 'static,
+                for<'this> &'this mut TThis: AsCraneliftAbi,
+                $($Args: 'static + AsCraneliftValue,)*
+            {
+                fn before(
+                    f: impl Fn(&mut TThis, &mut Self) + Send + Sync + 'static,
+                ) -> impl ThunkableClosure<TRet, TThis, Self> {
+                    move |
+                        ctx: &[<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
+                        this: &mut TThis,
+                        $($args: $Args,)*
+                    | -> TRet {
+                        let mut args = ($($args,)*);
+                        f(this, &mut args);
+                        call_original(ctx, this, args)
+                    }
+                }
+
+                fn after(
+                    f: impl Fn(&mut TThis, &Self, &mut TRet) + Send + Sync + 'static,
+                ) -> impl ThunkableClosure<TRet, TThis, Self>
+                where
+                    Self: Clone,
+                {
+                    move |
+                        ctx: &[<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
+                        this: &mut TThis,
+                        $($args: $Args,)*
+                    | -> TRet {
+                        let args = ($($args,)*);
+                        let mut ret = call_original(ctx, this, args.clone());
+                        f(this, &args, &mut ret);
+                        ret
+                    }
+                }
+            }
+
+            impl<
+                'ctx,
+                'state,
+                'this,
+                TClosure,
+                TState,
+                TRet,
+                TThis,
+                $($Args,)*
+            > StatefulClosure<TState, TRet, TThis, ($($Args,)*)> for TClosure
+            where
+                TState: Send + 'static,
+                TRet: 'static + AsCraneliftValue,
+                TThis: 'static,
+                for<'a> &'a mut TThis: AsCraneliftAbi,
+                $($Args: 'static + AsCraneliftValue,)*
+                TClosure: (
+                    FnMut(
+                        &'ctx [<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
+                        &'state mut TState,
+                        &'this mut TThis,
+                        $($Args,)*
+                    ) -> TRet
+                ) + Send + 'static,
+            {
+                type Closure = Box<dyn Fn(
+                    &[<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
+                    &mut TThis,
+                    $($Args,)*
+                ) -> TRet + Send + Sync + 'static>;
+
+                fn with_state(self, state: Arc<Mutex<TState>>) -> Self::Closure {
+                    // The closure is called with references that don't live as long as the ones it
+                    // was inferred with, like in into_raw_closure.
+                    let f = Box::new(self) as Box<dyn FnMut(
+                        &'ctx [<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
+                        &'state mut TState,
+                        &'this mut TThis,
+                        $($Args,)*
+                    ) -> TRet + Send + 'static>;
+                    #[allow(clippy::missing_transmute_annotations)]
+                    let f: Box<dyn FnMut(
+                        &[<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
+                        &mut TState,
+                        &mut TThis,
+                        $($Args,)*
+                    ) -> TRet + Send + 'static> = unsafe { std::mem::transmute(f) };
+                    let f = LockedClosure(UnsafeCell::new(f));
+
+                    Box::new(move |
+                        ctx: &[<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
+                        this: &mut TThis,
+                        $($args: $Args,)*
+                    | -> TRet {
+                        // The state is already locked further up this thread's stack.
+                        if ctx.depth() > 1 {
+                            return call_original(ctx, this, ($($args,)*));
+                        }
+
+                        let mut state = state.lock();
+                        unsafe { f.with(|f| f(ctx, &mut state, this, $($args,)*)) }
+                    })
                 }
             }
 
@@ -151,10 +440,10 @@ macro_rules! impl_func {
                 $($Args,)*
             > ThunkableClosure<TRet, TThis, ($($Args,)*)> for TClosure
             where
-                TRet: 'static + AsCraneliftAbi,
+                TRet: 'static + AsCraneliftValue,
                 TThis: 'static,
                 &'this mut TThis: AsCraneliftAbi,
-                $($Args: 'static + AsCraneliftAbi,)*
+                $($Args: 'static + AsCraneliftValue,)*
                 TClosure: (
                     Fn(
                         &'ctx [<_FuncContext $($Args )*>]<TRet, TThis, $($Args,)*>,
@@ -165,22 +454,25 @@ macro_rules! impl_func {
             {
                 fn thunk(&self) -> *const () {
                     unsafe extern "C" fn func<TRet, TThis, $($Args,)*>(
-                        index: usize,
+                        slot: *const HookSlot,
                         this: *mut TThis,
                         $($args: $Args,)*
                     ) -> TRet
                     where
-                        TRet: 'static,
+                        TRet: 'static + AsCraneliftValue,
                         TThis: 'static,
                         $(
                             $Args: 'static
                         ),*
                     {
-                        // Get the closure, original_fn and instance
+                        let slot = &*slot;
+                        let _in_flight = slot.enter();
+
+                        // Get the closure and original_fn
+                        #[allow(clippy::missing_transmute_annotations)]
                         let (
                                 closure,
                                 original_function,
-                                hook_instance,
                             ): (
                                 // Closure trait type, should match TClosure above but with dyn
                                 // TODO(emily): You could (and should) be passing down the lifetimes from the outer
@@ -195,57 +487,60 @@ macro_rules! impl_func {
 
                                 // Original function type
                                 [<_RawFunc $($Args )*>]<TRet, TThis, $($Args,)*>,
-
-                                // Hook instance
-                                Arc<Mutex<HookInstance>>,
-                            ) = {
-                            let hook_instance = HookInstance::for_instance(this as *mut ());
-                            let h = hook_instance.lock();
-                            let closure = h.closure(index);
-                            let original_function = h.original_function(index);
-
-                            drop(h);
-
-                            #[allow(clippy::missing_transmute_annotations)]
-                            (
-                                std::mem::transmute(closure),
-                                std::mem::transmute(original_function),
-                                hook_instance,
-                            )
-                        };
+                            ) = (
+                                std::mem::transmute(slot.closure),
+                                std::mem::transmute(slot.original.load(Ordering::Acquire)),
+                            );
+
+                        // The hook is being removed or skips this call, so don't call the closure.
+                        if slot.passes_through() {
+                            return [<_call_member $($Args )*>](
+                                original_function as *const (),
+                                this,
+                                $($args,)*
+                            );
+                        }
 
                         let context = [<_FuncContext $($Args )*>]::<TRet, TThis, $($Args,)*> {
                             original_fn: original_function,
-                            hook_instance
+                            hook: HookContext::new(slot),
                         };
 
-                        (*closure)(&context, &mut *this, $($args,)*)
+                        // The closure takes the arguments, so keep a copy of them for calling the
+                        // original function if it panics.
+                        let args = std::mem::ManuallyDrop::new(($($args,)*));
+                        let ($($args,)*) = std::ptr::read(&*args);
+
+                        let ret = slot.timed(|| {
+                            super::unwind::catch_panic(
+                                slot,
+                                || (*closure)(&context, &mut *this, $($args,)*),
+                                || {
+                                    let ($($args,)*) = std::mem::ManuallyDrop::into_inner(args);
+                                    [<_call_member $($Args )*>](
+                                        original_function as *const (),
+                                        this,
+                                        $($args,)*
+                                    )
+                                },
+                                |value| super::unwind::from_raw(value),
+                            )
+                        });
+                        slot.unhook_if_requested();
+                        ret
                     }
 
                     func::<TRet, TThis, $($Args,)*> as *const ()
                 }
 
-                fn thunk_cranelift_sig(&self, module: &mut JITModule) -> cranelift::prelude::Signature {
-                    let mut signature = module.make_signature();
-                    signature.returns.push(cranelift_abi::<TRet>());
-
-                    signature.params.push(AbiParam::new(types::I64));
-                    signature.params.push(cranelift_abi::<*mut TThis>());
-                    $(
-                        signature.params.push(cranelift_abi::<$Args>());
-                    )*
-                    signature
-                }
-
-                fn original_cranelift_sig(&self, module: &mut JITModule) -> cranelift::prelude::Signature {
-                    let mut signature = module.make_signature();
-                    signature.returns.push(cranelift_abi::<TRet>());
-
-                    signature.params.push(cranelift_abi::<*mut TThis>());
-                    $(
-                        signature.params.push(cranelift_abi::<$Args>());
-                    )*
-                    signature
+                fn cranelift_values(&self) -> (CraneliftValue, Vec<CraneliftValue>) {
+                    (
+                        cranelift_value::<TRet>(),
+                        vec![
+                            cranelift_value::<*mut TThis>(),
+                            $(cranelift_value::<$Args>(),)*
+                        ],
+                    )
                 }
 
                 fn into_raw_closure(self) -> *const dyn Fn() {
@@ -269,6 +564,101 @@ impl_func!(a: A, b: B);
 impl_func!(a: A, b: B, c: C);
 impl_func!(a: A, b: B, c: C, d: D);
 impl_func!(a: A, b: B, c: C, d: D, e: E);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K, l: L);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K, l: L, m: M);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K, l: L, m: M, n: N);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K, l: L, m: M, n: N, o: O);
+impl_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H, i: I, j: J, k: K, l: L, m: M, n: N, o: O, p: P);
+
+/// What the closure of a hook can find out about the hook, and do to it. The contexts that closures
+/// are called with dereference to this.
+///
+/// The type of `ctx` in a closure isn't known until the closure has been checked, so its methods
+/// can't be called on it directly unless its type is written out. Get the [`HookContext`] with
+/// [`HookContext::of`] or [`HookContext::with_current`] instead:
+///
+/// ```rs
+/// |ctx, this: &mut Entity, arg: usize| -> usize {
+///     if HookContext::of(ctx).index() == 10 {
+///         // ...
+///     }
+///     HookContext::with_current(|hook| println!("{:?}", hook.instance()));
+///     call_original(ctx, this, (arg,))
+/// }
+/// ```
+///
+#[derive(Debug)]
+pub struct HookContext {
+    slot: *const HookSlot,
+}
+
+impl HookContext {
+    pub(super) fn new(slot: &HookSlot) -> Self {
+        Self { slot }
+    }
+
+    /// Get the [`HookContext`] of the context that a closure is called with.
+    pub fn of<C: std::ops::Deref<Target = HookContext>>(ctx: &C) -> &HookContext {
+        ctx
+    }
+
+    /// Call `f` with the [`HookContext`] of the innermost call of a hook on this thread, which is
+    /// the hook whose closure is running when this is called from a closure. Returns `None` if no
+    /// hook is being called on this thread.
+    pub fn with_current<R>(f: impl FnOnce(&HookContext) -> R) -> Option<R> {
+        let slot = HookSlot::innermost()?;
+
+        Some(f(&HookContext { slot }))
+    }
+
+    fn slot(&self) -> &HookSlot {
+        unsafe { &*self.slot }
+    }
+
+    /// How many calls of this hook are in progress on this thread, including this one. This is more
+    /// than 1 when the closure has called something that called the hooked function again.
+    pub fn depth(&self) -> usize {
+        self.slot().depth()
+    }
+
+    /// The index of the hooked function in its vtable.
+    pub fn index(&self) -> usize {
+        self.slot().target().index
+    }
+
+    /// The hooked instance, or `None` for a [`crate::VtableHook`], which hooks every instance using
+    /// its vtable.
+    pub fn instance(&self) -> Option<*mut ()> {
+        let instance = self.slot().target().instance;
+        (!instance.is_null()).then_some(instance)
+    }
+
+    /// Unhook this hook once the current call of it returns, for hooks that should only run once or
+    /// a few times. Calls that are already in the closure on other threads carry on.
+    ///
+    /// The [`crate::HookFunction`] or [`crate::VtableHook`] that owned the hook can still be dropped
+    /// afterwards, which does nothing.
+    pub fn unhook_after_return(&self) {
+        self.slot().unhook_requested.store(true, Ordering::SeqCst);
+    }
+
+    /// The original function at `index` of the same vtable, without any hooks of it, so that other
+    /// methods of the instance can be called from the closure.
+    ///
+    /// Returns `None` if `index` is outside of the vtable of a [`crate::HookFunction`]. The length of
+    /// the vtable of a [`crate::VtableHook`] isn't known, so it isn't checked.
+    pub fn original(&self, index: usize) -> Option<OriginalFunction> {
+        self.slot()
+            .original_function(index)
+            .map(OriginalFunction::new)
+    }
+}
 
 /// Call the original function for a context.
 pub fn call_original<TRet: 'static, TThis: 'static, TArgs: 'static>(
@@ -279,29 +669,163 @@ pub fn call_original<TRet: 'static, TThis: 'static, TArgs: 'static>(
     ctx.call(this, args)
 }
 
-pub(super) struct TrampolineStorage {
-    module: Mutex<JITModule>,
+/// Make a module to JIT compile hooks into.
+fn new_module() -> Result<JITModule> {
+    let mut flags = settings::builder();
+    // i128 arguments are only supported when they are split into two registers like LLVM does.
+    flags.set("enable_llvm_abi_extensions", "true")?;
+    // Hooks only call through addresses that are baked in, so they don't need the GOT and PLT that
+    // come with PIC, whose entries have to be within 2GB of each other. The memory of a module that
+    // lives for a long time can end up further apart than that.
+    flags.set("is_pic", "false")?;
+    flags.set("use_colocated_libcalls", "false")?;
+
+    let isa = cranelift::native::builder()
+        .map_err(|error| anyhow!("the host isn't supported by cranelift: {error}"))?
+        .finish(settings::Flags::new(flags))?;
+
+    Ok(JITModule::new(JITBuilder::with_isa(
+        isa,
+        cranelift_module::default_libcall_names(),
+    )))
+}
+
+/// How many hooks put their code in the same module before a new module is started.
+///
+/// Cranelift can only free the code of a module all at once, so a module is only freed once every
+/// hook in it has been. Sharing modules saves making an ISA and mapping new pages for every hook, at
+/// the cost of a hook that is never dropped keeping the code of up to this many other hooks alive.
+const HOOKS_PER_MODULE: usize = 32;
+
+/// A module in the [`ModulePool`].
+struct PooledModule {
+    module: JITModule,
+    // Number of hooks that have been given the module, and how many of them haven't been freed.
+    hooks: usize,
+    live: usize,
+}
+
+/// The modules that the code of every hook in the process is JIT compiled into.
+#[derive(Default)]
+struct ModulePool {
+    modules: HashMap<usize, PooledModule>,
+    // The module that new hooks are given, until it has HOOKS_PER_MODULE of them.
+    current: Option<usize>,
+    next_id: usize,
 }
 
-impl TrampolineStorage {
+// The modules are only used while the pool is locked.
+unsafe impl Send for ModulePool {}
+
+impl ModulePool {
+    fn get() -> MutexGuard<'static, ModulePool> {
+        static MODULE_POOL: OnceLock<Mutex<ModulePool>> = OnceLock::new();
+
+        MODULE_POOL.get_or_init(Default::default).lock()
+    }
+
+    /// Give a new hook a module, starting a new one if the current one is full, and return its id.
+    fn take(&mut self) -> Result<usize> {
+        let current = self
+            .current
+            .filter(|id| self.modules[id].hooks < HOOKS_PER_MODULE);
+
+        let id = match current {
+            Some(id) => id,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+
+                self.modules.insert(
+                    id,
+                    PooledModule {
+                        module: new_module()?,
+                        hooks: 0,
+                        live: 0,
+                    },
+                );
+                self.current = Some(id);
+
+                id
+            }
+        };
+
+        let module = self.modules.get_mut(&id).unwrap();
+        module.hooks += 1;
+        module.live += 1;
+
+        Ok(id)
+    }
+
+    /// Release the place of a freed hook in module `id`, freeing the module if it's full and this was
+    /// the last hook in it.
+    fn release(&mut self, id: usize) {
+        let module = self.modules.get_mut(&id).unwrap();
+        module.live -= 1;
+
+        if module.live == 0 && module.hooks >= HOOKS_PER_MODULE {
+            let module = self.modules.remove(&id).unwrap();
+            unsafe { module.module.free_memory() };
+        }
+    }
+}
+
+/// The code made for a single hook, which is freed with the rest of its module once every hook in the
+/// module has been dropped, see [`HOOKS_PER_MODULE`].
+pub(super) struct HookCode {
+    // The id of the module in the ModulePool.
+    module: usize,
+    // Bridges to original functions by the address of the original, which are made by
+    // ThunkableClosure::make_original_bridge.
+    bridges: HashMap<usize, usize>,
+}
+
+impl HookCode {
     pub(super) fn new() -> Result<Self> {
-        let builder = JITBuilder::new(cranelift_module::default_libcall_names())?;
-        let module = JITModule::new(builder);
         Ok(Self {
-            module: Mutex::new(module),
+            module: ModulePool::get().take()?,
+            bridges: HashMap::new(),
         })
     }
 
-    pub(super) fn module(&self) -> MutexGuard<JITModule> {
-        self.module.lock()
+    /// Call `define` with the module of the hook to compile code into it.
+    pub(super) fn define<R>(&self, define: impl FnOnce(&mut JITModule) -> Result<R>) -> Result<R> {
+        let mut pool = ModulePool::get();
+        define(&mut pool.modules.get_mut(&self.module).unwrap().module)
+    }
+
+    /// Get the bridge to `original`, making it with `make` if there isn't one yet.
+    ///
+    /// Bridges are kept until the hook is freed, as calls could still be in a bridge to the function
+    /// that the hook used to call.
+    pub(super) fn original_bridge(
+        &mut self,
+        original: *const (),
+        make: impl FnOnce(&mut JITModule) -> Result<Option<*const ()>>,
+    ) -> Result<Option<*const ()>> {
+        if let Some(bridge) = self.bridges.get(&(original as usize)) {
+            return Ok(Some(*bridge as *const ()));
+        }
+
+        let bridge = self.define(make)?;
+
+        if let Some(bridge) = bridge {
+            self.bridges.insert(original as usize, bridge as usize);
+        }
+
+        Ok(bridge)
     }
 }
 
-impl Drop for TrampolineStorage {
+impl std::fmt::Debug for HookCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookCode").finish_non_exhaustive()
+    }
+}
+
+impl Drop for HookCode {
     fn drop(&mut self) {
-        // TODO(emily): You should probably call free_memory here to not leak memory.
-        // let module = self.module.lock();
-        // module.free_memory();
+        ModulePool::get().release(self.module);
     }
 }
 
@@ -309,9 +833,9 @@ pub trait AsCraneliftAbi {
     fn as_cranelift_abi() -> AbiParam;
 }
 
-/// Get the cranelift abi for a type T
-fn cranelift_abi<T: AsCraneliftAbi>() -> AbiParam {
-    T::as_cranelift_abi()
+/// Get the cranelift value for a type T
+fn cranelift_value<T: AsCraneliftValue>() -> CraneliftValue {
+    T::as_cranelift_value()
 }
 
 // Implement AsCraneliftAbi for a bunch of types
@@ -340,6 +864,80 @@ impl<T> AsCraneliftAbi for &T {
     }
 }
 
+impl<T> AsCraneliftAbi for NonNull<T> {
+    fn as_cranelift_abi() -> AbiParam {
+        AbiParam::new(types::I64)
+    }
+}
+
+// Option of a non-nullable pointer is guaranteed to be the same as the pointer, with None as null.
+
+impl<T> AsCraneliftAbi for Option<&mut T> {
+    fn as_cranelift_abi() -> AbiParam {
+        AbiParam::new(types::I64)
+    }
+}
+
+impl<T> AsCraneliftAbi for Option<&T> {
+    fn as_cranelift_abi() -> AbiParam {
+        AbiParam::new(types::I64)
+    }
+}
+
+impl<T> AsCraneliftAbi for Option<NonNull<T>> {
+    fn as_cranelift_abi() -> AbiParam {
+        AbiParam::new(types::I64)
+    }
+}
+
+/// Implement AsCraneliftAbi for `extern "C"` function pointers (and options of them) taking these
+/// arguments.
+macro_rules! cranelift_abi_fn {
+    ($($Args:ident),*) => {
+        impl<R, $($Args,)*> AsCraneliftAbi for extern "C" fn($($Args,)*) -> R {
+            fn as_cranelift_abi() -> AbiParam {
+                AbiParam::new(types::I64)
+            }
+        }
+
+        impl<R, $($Args,)*> AsCraneliftAbi for unsafe extern "C" fn($($Args,)*) -> R {
+            fn as_cranelift_abi() -> AbiParam {
+                AbiParam::new(types::I64)
+            }
+        }
+
+        impl<R, $($Args,)*> AsCraneliftAbi for Option<extern "C" fn($($Args,)*) -> R> {
+            fn as_cranelift_abi() -> AbiParam {
+                AbiParam::new(types::I64)
+            }
+        }
+
+        impl<R, $($Args,)*> AsCraneliftAbi for Option<unsafe extern "C" fn($($Args,)*) -> R> {
+            fn as_cranelift_abi() -> AbiParam {
+                AbiParam::new(types::I64)
+            }
+        }
+    };
+}
+
+cranelift_abi_fn!();
+cranelift_abi_fn!(A);
+cranelift_abi_fn!(A, B);
+cranelift_abi_fn!(A, B, C);
+cranelift_abi_fn!(A, B, C, D);
+cranelift_abi_fn!(A, B, C, D, E);
+cranelift_abi_fn!(A, B, C, D, E, F);
+cranelift_abi_fn!(A, B, C, D, E, F, G);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I, J);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I, J, K);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I, J, K, L);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I, J, K, L, M);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I, J, K, L, M, N);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O);
+cranelift_abi_fn!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P);
+
 macro_rules! cranelift_abi {
     ($t:ty, $abi:ident) => {
         impl AsCraneliftAbi for $t {
@@ -353,6 +951,12 @@ macro_rules! cranelift_abi {
 cranelift_abi!(f32, F32);
 cranelift_abi!(f64, F64);
 
+cranelift_abi!(u128, I128);
+cranelift_abi!(i128, I128);
+
+cranelift_abi!(u64, I64);
+cranelift_abi!(i64, I64);
+
 cranelift_abi!(usize, I64);
 cranelift_abi!(isize, I64);
 
@@ -364,3 +968,6 @@ cranelift_abi!(i16, I16);
 
 cranelift_abi!(u8, I8);
 cranelift_abi!(i8, I8);
+
+cranelift_abi!(char, I32);
+cranelift_abi!(bool, I8);
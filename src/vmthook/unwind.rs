@@ -0,0 +1,124 @@
+//! Keeping panics in hook closures from unwinding into the code that called the hooked function,
+//! which can't unwind through Rust frames.
+
+use std::{
+    any::Any,
+    mem::MaybeUninit,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, OnceLock},
+};
+
+use parking_lot::{Mutex, MutexGuard};
+
+use super::{dynamic::RawValue, HookSlot};
+
+/// What happens when the closure of a hook panics, set with [`crate::HookOptions::on_panic`].
+///
+/// The panic is passed to the handler set with [`set_panic_handler`] first in every case.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Abort the process.
+    #[default]
+    Abort,
+    /// Call the original function with the arguments that the closure was called with, and return
+    /// what it returns. Dynamic hooks call it with the arguments as the closure left them.
+    CallOriginal,
+    /// Return this value, which is the bits of the return value like for dynamic hooks. It is zero
+    /// extended to the size of the return type, and must be valid for it.
+    Return(RawValue),
+}
+
+/// A panic in the closure of a hook, which is passed to the handler set with [`set_panic_handler`].
+#[derive(Debug)]
+pub struct HookPanic<'a> {
+    /// The index of the hooked function in its vtable.
+    pub index: usize,
+    /// The hooked instance, or `None` for a [`crate::VtableHook`].
+    pub instance: Option<*mut ()>,
+    /// What the closure panicked with.
+    pub payload: &'a (dyn Any + Send),
+}
+
+impl HookPanic<'_> {
+    /// The message that the closure panicked with, if it panicked with a string.
+    pub fn message(&self) -> Option<&str> {
+        self.payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| self.payload.downcast_ref::<String>().map(String::as_str))
+    }
+}
+
+type PanicHandler = Arc<dyn Fn(&HookPanic) + Send + Sync>;
+
+fn panic_handler() -> MutexGuard<'static, Option<PanicHandler>> {
+    static PANIC_HANDLER: OnceLock<Mutex<Option<PanicHandler>>> = OnceLock::new();
+
+    PANIC_HANDLER.get_or_init(Default::default).lock()
+}
+
+/// Call `handler` with every panic in the closure of a hook, replacing the previous handler, so that
+/// they can be logged.
+pub fn set_panic_handler(handler: impl Fn(&HookPanic) + Send + Sync + 'static) {
+    *panic_handler() = Some(Arc::new(handler));
+}
+
+/// Call `closure`, and handle a panic in it with the policy of `slot`. `original` calls the original
+/// function instead, and `from_raw` makes a return value from the one in the policy.
+pub(super) fn catch_panic<R>(
+    slot: &HookSlot,
+    closure: impl FnOnce() -> R,
+    original: impl FnOnce() -> R,
+    from_raw: impl FnOnce(RawValue) -> R,
+) -> R {
+    let payload = match catch_unwind(AssertUnwindSafe(closure)) {
+        Ok(ret) => return ret,
+        Err(payload) => payload,
+    };
+
+    let target = slot.target();
+    let panic = HookPanic {
+        index: target.index,
+        instance: (!target.instance.is_null()).then_some(target.instance),
+        payload: &*payload,
+    };
+
+    // The handler could set another handler, so don't keep it locked.
+    let handler = panic_handler().clone();
+
+    if let Some(handler) = handler {
+        handler(&panic);
+    }
+
+    match slot.on_panic {
+        PanicPolicy::Abort => {
+            eprintln!(
+                "the hook of function {} of {:?} panicked with {:?}, aborting",
+                panic.index,
+                target.instance,
+                panic.message().unwrap_or("a non-string payload"),
+            );
+            std::process::abort()
+        }
+        PanicPolicy::CallOriginal => original(),
+        PanicPolicy::Return(value) => from_raw(value),
+    }
+}
+
+/// Make a `R` from the bits in `value`, zero extending them.
+///
+/// # Safety
+/// * The bits must be valid for `R`.
+///
+pub(super) unsafe fn from_raw<R>(value: RawValue) -> R {
+    let mut ret = MaybeUninit::<R>::zeroed();
+    let len = std::mem::size_of::<R>().min(std::mem::size_of::<RawValue>());
+
+    std::ptr::copy_nonoverlapping(
+        &value.0 as *const u128 as *const u8,
+        ret.as_mut_ptr() as *mut u8,
+        len,
+    );
+
+    ret.assume_init()
+}
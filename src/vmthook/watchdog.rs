@@ -0,0 +1,85 @@
+use std::{sync::Arc, thread::JoinHandle, time::Duration};
+
+use parking_lot::{Condvar, Mutex};
+
+use super::{HookInstance, TableReplaced};
+
+/// A thread that looks for hooked instances whose vtable pointer was replaced by something else, and
+/// optionally points them back at their shadow tables.
+///
+/// Replacements are passed to the handler set with [`HookInstance::set_replaced_handler`].
+///
+/// Instances are read from the watchdog's thread, so every hooked instance has to outlive its hooks,
+/// or have its destructor tracked with [`HookInstance::track_destructor`] or be forgotten with
+/// [`HookInstance::forget`] before it's destroyed.
+#[derive(Debug)]
+pub struct Watchdog {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Check every hooked instance each `interval`, pointing the ones that were replaced back at their
+    /// shadow tables if `rehook`. The thread is stopped when the [`Watchdog`] is dropped.
+    pub fn new(interval: Duration, rehook: bool) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+
+            move || {
+                let (stopped, condvar) = &*stop;
+                let mut stopped = stopped.lock();
+
+                while !*stopped {
+                    condvar.wait_for(&mut stopped, interval);
+
+                    if !*stopped {
+                        Self::check_all(rehook);
+                    }
+                }
+            }
+        });
+
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Check every hooked instance once on this thread, like the watchdog thread does, and return the
+    /// ones that were replaced.
+    pub fn check_all(rehook: bool) -> Vec<TableReplaced> {
+        let mut found = vec![];
+
+        for hook in HookInstance::hooked_instances() {
+            let mut hook = hook.lock();
+
+            let replaced = if rehook {
+                hook.rehook_table()
+            } else {
+                hook.replaced()
+            };
+            drop(hook);
+
+            if let Some(replaced) = replaced {
+                HookInstance::report_replaced(&replaced);
+                found.push(replaced);
+            }
+        }
+
+        found
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}